@@ -1,22 +1,54 @@
 use std::sync::OnceLock;
 
-use proc_macro2::Span;
-use quote::quote;
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote, quote_spanned};
 use regex::Regex;
 use syn::{
-    parse_macro_input, punctuated::Punctuated, BareFnArg, Error, Expr, ExprAssign, ExprLit,
-    ExprPath, FnArg, Ident, ItemFn, Lit, LitStr, Result, Token, TypeBareFn,
+    parenthesized,
+    parse::{ParseStream, Parser},
+    parse_macro_input,
+    punctuated::Punctuated,
+    Attribute, Block, DeriveInput, Error, Expr, ExprAssign, ExprLit, ExprPath, Field, Fields,
+    FnArg, ForeignItemFn, ForeignItemStatic, Ident, ImplItem, Item, ItemImpl, ItemMod, ItemStruct,
+    ItemTrait, Lit, LitStr, Pat, PatIdent, PatType, Path, Receiver, Result, Signature, Stmt, Token,
+    TraitItem, Type, Visibility,
 };
 
 enum Address {
-    Signature(String),
+    /// Patterns to scan the module for, tried in order.
+    Signatures(Vec<String>),
+    /// A pattern matching a call or jump, and the offset of its rel32 operand within the
+    /// pattern. The address is the call or jump's target.
+    Callsite { pattern: String, offset: Box<Expr> },
+    /// An expression evaluating to a `usize` offset from the module's base.
+    Rva(Box<Expr>),
+    /// The name of a function exported by the module.
+    Export(String),
     /// An arbitrary expression evaluating to a `usize` address: an integer literal
     /// (`0x1234`) or a path to a constant (`some::module::Type::FN_ADDRESS`).
-    Address(Box<Expr>),
+    Absolute(Box<Expr>),
+}
+
+/// What a detour does when its body panics, after the panic has been reported.
+enum PanicPolicy {
+    CallOriginal,
+    ReturnDefault,
+    Abort,
 }
 
 struct Args {
     pub address: Address,
+    /// The module to search, or the main executable if `None`.
+    pub module: Option<LitStr>,
+    pub panic: Option<PanicPolicy>,
+    /// The group the detour belongs to, for selecting detours from a `#[hook_library]`.
+    pub group: Option<LitStr>,
+    /// How many pointers to follow to reach a `#[game_static]`.
+    pub deref: Option<Expr>,
+    /// The hook engine used for a detour, or the default engine if `None`.
+    pub engine: Option<Path>,
+    /// Where a detour goes in the chain of detours on its function, or 0 if `None`.
+    pub priority: Option<Expr>,
 }
 
 fn pattern_regex() -> &'static Regex {
@@ -24,9 +56,44 @@ fn pattern_regex() -> &'static Regex {
     PATTERN_REGEX.get_or_init(|| Regex::new(r"^(([0-9A-Z]{2}|\?)\s)*([0-9A-Z]{2}|\?)$").unwrap())
 }
 
+fn parse_string(expr: &Expr, name: &str) -> Result<LitStr> {
+    match expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Str(lit), ..
+        }) => Ok(lit.clone()),
+        _ => Err(Error::new_spanned(
+            expr,
+            format!("`{}` must be a literal string", name),
+        )),
+    }
+}
+
+fn parse_pattern(expr: &Expr, name: &str) -> Result<String> {
+    let lit = parse_string(expr, name)?;
+    if pattern_regex().is_match(&lit.value()) {
+        Ok(lit.value())
+    } else {
+        Err(Error::new_spanned(
+            lit,
+            format!(
+                "`{}` is invalid, does not match pattern format (`DE ? BE EF`)",
+                name
+            ),
+        ))
+    }
+}
+
 impl Args {
     fn new(args: Punctuated<Expr, Token![,]>) -> Result<Self> {
         let mut address = None;
+        let mut module = None;
+        let mut offset = None;
+        let mut rel32_at = None;
+        let mut panic = None;
+        let mut group = None;
+        let mut deref = None;
+        let mut engine = None;
+        let mut priority = None;
 
         for arg in args {
             let Expr::Assign(ExprAssign { left, right, .. }) = arg else {
@@ -36,144 +103,1118 @@ impl Args {
                 return Err(Error::new_spanned(&left, "expected an attribute name"));
             };
 
-            if path.is_ident("pattern") {
-                if address.is_some() {
+            if path.is_ident("module") {
+                if module.is_some() {
                     return Err(Error::new_spanned(
                         path,
-                        "address has already been specified",
+                        "module has already been specified",
                     ));
                 }
-
-                if let Expr::Lit(ExprLit {
-                    lit: Lit::Str(lit), ..
-                }) = right.as_ref()
-                {
-                    if pattern_regex().is_match(&lit.value()) {
-                        address = Some(Address::Signature(lit.value()));
-                    } else {
-                        return Err(Error::new_spanned(
-                            lit,
-                            "`pattern` is invalid, does not match pattern format (`DE ? BE EF`)",
-                        ));
-                    }
-                } else {
+                module = Some(parse_string(&right, "module")?);
+                continue;
+            } else if path.is_ident("offset") {
+                if offset.is_some() {
                     return Err(Error::new_spanned(
-                        &right,
-                        "`pattern` must be a literal string",
+                        path,
+                        "offset has already been specified",
                     ));
                 }
-            } else if path.is_ident("address") {
-                if address.is_some() {
+                offset = Some(right);
+                continue;
+            } else if path.is_ident("rel32_at") {
+                if rel32_at.is_some() {
+                    return Err(Error::new_spanned(
+                        path,
+                        "rel32_at has already been specified",
+                    ));
+                }
+                rel32_at = Some(right);
+                continue;
+            } else if path.is_ident("deref") {
+                if deref.is_some() {
+                    return Err(Error::new_spanned(path, "deref has already been specified"));
+                }
+                deref = Some(*right);
+                continue;
+            } else if path.is_ident("engine") {
+                if engine.is_some() {
                     return Err(Error::new_spanned(
                         path,
-                        "address has already been specified",
+                        "engine has already been specified",
                     ));
                 }
+                let Expr::Path(ExprPath {
+                    path: engine_path, ..
+                }) = *right
+                else {
+                    return Err(Error::new_spanned(
+                        right,
+                        "`engine` must be a path to a type",
+                    ));
+                };
+                engine = Some(engine_path);
+                continue;
+            } else if path.is_ident("priority") {
+                if priority.is_some() {
+                    return Err(Error::new_spanned(
+                        path,
+                        "priority has already been specified",
+                    ));
+                }
+                priority = Some(*right);
+                continue;
+            } else if path.is_ident("group") {
+                if group.is_some() {
+                    return Err(Error::new_spanned(path, "group has already been specified"));
+                }
+                group = Some(parse_string(&right, "group")?);
+                continue;
+            } else if path.is_ident("panic") {
+                if panic.is_some() {
+                    return Err(Error::new_spanned(path, "panic has already been specified"));
+                }
+                let policy = parse_string(&right, "panic")?;
+                panic = Some(match policy.value().as_str() {
+                    "original" => PanicPolicy::CallOriginal,
+                    "default" => PanicPolicy::ReturnDefault,
+                    "abort" => PanicPolicy::Abort,
+                    _ => {
+                        return Err(Error::new_spanned(
+                            policy,
+                            "`panic` must be one of `original`, `default` or `abort`",
+                        ))
+                    }
+                });
+                continue;
+            }
 
+            if address.is_some() {
+                return Err(Error::new_spanned(
+                    path,
+                    "address has already been specified",
+                ));
+            }
+            address = Some(if path.is_ident("pattern") {
+                // Either a single pattern, or an array of fallback patterns.
+                match right.as_ref() {
+                    Expr::Array(array) => {
+                        if array.elems.is_empty() {
+                            return Err(Error::new_spanned(
+                                array,
+                                "`pattern` must contain at least one pattern",
+                            ));
+                        }
+                        Address::Signatures(
+                            array
+                                .elems
+                                .iter()
+                                .map(|pattern| parse_pattern(pattern, "pattern"))
+                                .collect::<Result<Vec<_>>>()?,
+                        )
+                    }
+                    pattern => Address::Signatures(vec![parse_pattern(pattern, "pattern")?]),
+                }
+            } else if path.is_ident("callsite") {
+                Address::Callsite {
+                    pattern: parse_pattern(&right, "callsite")?,
+                    offset: Box::new(syn::parse_quote!(1)),
+                }
+            } else if path.is_ident("rva") {
+                Address::Rva(right)
+            } else if path.is_ident("export") {
+                // Either `name`, or `module!name`.
+                let export = parse_string(&right, "export")?;
+                match export.value().split_once('!') {
+                    Some((export_module, name)) => {
+                        if module.is_some() {
+                            return Err(Error::new_spanned(
+                                export,
+                                "module has already been specified",
+                            ));
+                        }
+                        module = Some(LitStr::new(export_module, export.span()));
+                        Address::Export(name.to_owned())
+                    }
+                    None => Address::Export(export.value()),
+                }
+            } else if path.is_ident("address") {
                 // Accept any expression evaluating to a `usize`: an integer literal
                 // (`0x1234`) or a path to a constant (`Type::FN_ADDRESS`).
-                address = Some(Address::Address(right));
+                Address::Absolute(right)
             } else {
                 return Err(Error::new_spanned(path, "unknown attribute"));
+            });
+        }
+
+        let mut address =
+            address.ok_or_else(|| Error::new(Span::call_site(), "missing `address` attribute"))?;
+        if let (Address::Absolute(_), Some(module)) = (&address, &module) {
+            return Err(Error::new_spanned(
+                module,
+                "`module` cannot be used with an absolute `address`",
+            ));
+        }
+        if let Some(new_offset) = offset {
+            let Address::Callsite { offset, .. } = &mut address else {
+                return Err(Error::new_spanned(
+                    new_offset,
+                    "`offset` can only be used with `callsite`",
+                ));
+            };
+            *offset = new_offset;
+        }
+        // A rel32 operand in a pattern is resolved the same way as a callsite's.
+        if let Some(rel32_at) = rel32_at {
+            let Address::Signatures(patterns) = &mut address else {
+                return Err(Error::new_spanned(
+                    rel32_at,
+                    "`rel32_at` can only be used with `pattern`",
+                ));
+            };
+            if patterns.len() != 1 {
+                return Err(Error::new_spanned(
+                    rel32_at,
+                    "`rel32_at` can only be used with a single pattern",
+                ));
             }
+            address = Address::Callsite {
+                pattern: patterns.remove(0),
+                offset: rel32_at,
+            };
         }
 
         Ok(Self {
-            address: address
-                .ok_or_else(|| Error::new(Span::call_site(), "missing `address` attribute"))?,
+            address,
+            module,
+            panic,
+            group,
+            deref,
+            engine,
+            priority,
         })
     }
 }
 
+impl Args {
+    fn reject_detour_arguments(&self, attribute: &str) -> Result<()> {
+        if self.panic.is_some()
+            || self.group.is_some()
+            || self.engine.is_some()
+            || self.priority.is_some()
+        {
+            return Err(Error::new(
+                Span::call_site(),
+                format!(
+                    "`panic`, `group`, `engine` and `priority` cannot be used with `{}`",
+                    attribute
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn reject_game_static_arguments(&self, attribute: &str) -> Result<()> {
+        if let Some(deref) = &self.deref {
+            return Err(Error::new_spanned(
+                deref,
+                format!("`deref` cannot be used with `{}`", attribute),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Detours a function, generating a `static` holding the detour and a `_BINDER` static that
+/// binds and enables it.
+///
+/// The function is found with one of:
+/// - `address = expr`: an absolute address;
+/// - `pattern = "..."`, or `pattern = ["...", "..."]` to try several patterns in order;
+/// - `callsite = "...", offset = n`: the target of the call or jump whose rel32 operand is at
+///   offset `n` (1 by default) in the pattern;
+/// - `pattern = "...", rel32_at = n`: the target of the rel32 operand at offset `n` in the
+///   pattern, which must be the end of its instruction;
+/// - `rva = expr`: an offset from the module's base;
+/// - `export = "name"` or `export = "module.dll!name"`: an exported function.
+///
+/// Everything but `address` searches the module given by `module = "name.dll"`, or the main
+/// executable by default.
+///
+/// Inside the detour, `original(...)` calls the original function with the same signature.
+///
+/// Panics in the detour are caught and reported through
+/// `re_utilities::detour_panic::set_reporter`, and then handled according to
+/// `panic = "..."`:
+/// - `"abort"` (the default): abort the process;
+/// - `"original"`: return the result of calling the original function with the same arguments.
+///   They are copied before the body runs, so every argument must be named and `Copy` (e.g. no
+///   `String`s or `&mut` references);
+/// - `"default"`: return `Default::default()`.
+///
+/// `group = "name"` tags the detour, so that it can be selected from a `#[hook_library]`.
+///
+/// `engine = path::to::Engine` installs the detour with a `re_utilities::HookEngine` other than
+/// the default.
+///
+/// Any number of detours can hook the same function, even from different crates: they are
+/// called in order of descending `priority = n` (0 by default), each continuing to the next
+/// through `original(...)`. See `re_utilities::ChainedHook`.
+///
+/// To detour methods, put `#[detour]` on their impl block and `#[detour(...)]` on each method.
+/// `&self` and `&mut self` are passed to the original function as a raw `this` pointer (use
+/// `extern "thiscall"` for 32-bit member functions), and the statics are named after the type
+/// and the method (`GAME_TYPE_FOO`, `GAME_TYPE_FOO_BINDER`).
 #[proc_macro_attribute]
 pub fn detour(
     args: proc_macro::TokenStream,
     input: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
-    // Extract arguments
-    let args = match Args::new(parse_macro_input!(
-        args with Punctuated::<Expr, Token![,]>::parse_terminated
-    )) {
-        Ok(gen) => gen,
-        Err(err) => return err.to_compile_error().into(),
+    let result = match parse_macro_input!(input as Item) {
+        Item::Fn(mut detour) => Punctuated::<Expr, Token![,]>::parse_terminated
+            .parse(args)
+            .and_then(Args::new)
+            .and_then(|args| expand_detour(args, &detour.vis, &detour.sig, &mut detour.block, None))
+            .map(|statics| quote! { #statics #detour }),
+        Item::Impl(detours) => {
+            if args.is_empty() {
+                expand_impl(detours)
+            } else {
+                Err(Error::new(
+                    Span::call_site(),
+                    "arguments go on the methods of the impl block, as `#[detour(...)]`",
+                ))
+            }
+        }
+        item => Err(Error::new_spanned(
+            item,
+            "`#[detour]` can only be used on functions and impl blocks",
+        )),
+    };
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Declares a function in the game that is called without detouring it, such as
+/// `#[game_fn(pattern = "...")] pub extern "C" fn foo(a: i32) -> i32;`.
+///
+/// The function is found with the same arguments as `#[detour]`, the first time it is called
+/// (or when `FOO.resolve()` is called). Calling it panics if it cannot be found.
+#[proc_macro_attribute]
+pub fn game_fn(
+    args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let function = parse_macro_input!(input as ForeignItemFn);
+    let result = Punctuated::<Expr, Token![,]>::parse_terminated
+        .parse(args)
+        .and_then(Args::new)
+        .and_then(|args| expand_game_fn(args, function));
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand_game_fn(args: Args, function: ForeignItemFn) -> Result<TokenStream> {
+    args.reject_detour_arguments("#[game_fn]")?;
+    args.reject_game_static_arguments("#[game_fn]")?;
+
+    let ForeignItemFn {
+        attrs,
+        vis,
+        sig: signature,
+        ..
+    } = function;
+    let function_name = &signature.ident;
+    let static_name = format_ident!("{}", function_name.to_string().to_uppercase());
+    let entry_name = function_name.to_string();
+
+    let mut argument_names = vec![];
+    let mut argument_types = vec![];
+    for (i, arg) in signature.inputs.iter().enumerate() {
+        let FnArg::Typed(PatType { pat, ty, .. }) = arg else {
+            return Err(Error::new_spanned(
+                arg,
+                "game functions cannot take `self`; declare it as a pointer argument",
+            ));
+        };
+        argument_names.push(match pat.as_ref() {
+            Pat::Ident(PatIdent { ident, .. }) => ident.clone(),
+            _ => format_ident!("argument{}", i),
+        });
+        argument_types.push(ty);
+    }
+    if let Some(variadic) = &signature.variadic {
+        return Err(Error::new_spanned(
+            variadic,
+            "variadic game functions are not supported",
+        ));
+    }
+
+    let unsafety = signature.unsafety;
+    let abi = &signature.abi;
+    let output = &signature.output;
+    let function_type = quote! { #unsafety #abi fn(#(#argument_types),*) #output };
+    let address_block = address_block(args.address, args.module.as_ref(), &entry_name);
+
+    Ok(quote! {
+        #vis static #static_name: ::re_utilities::game_fn::GameFn<#function_type> =
+            ::re_utilities::game_fn::GameFn::new(#entry_name, || {
+                #address_block
+                Ok(address)
+            });
+
+        #(#attrs)*
+        #vis #unsafety fn #function_name(#(#argument_names: #argument_types),*) #output {
+            (#static_name.get())(#(#argument_names),*)
+        }
+    })
+}
+
+/// Declares a global variable in the game, such as
+/// `#[game_static(pattern = "48 8B 05 ? ? ? ?", rel32_at = 3)] static WORLD: *mut World;`.
+///
+/// The global is found with the same arguments as `#[detour]`, the first time it is used (or
+/// when `WORLD.resolve()` is called), and is accessed through the generated `GameStatic`.
+/// `deref = n` follows `n` pointers from the found address to reach the global, on every
+/// access.
+#[proc_macro_attribute]
+pub fn game_static(
+    args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let global = parse_macro_input!(input as ForeignItemStatic);
+    let result = Punctuated::<Expr, Token![,]>::parse_terminated
+        .parse(args)
+        .and_then(Args::new)
+        .and_then(|args| expand_game_static(args, global));
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand_game_static(args: Args, global: ForeignItemStatic) -> Result<TokenStream> {
+    args.reject_detour_arguments("#[game_static]")?;
+    if let Some(mutability) = global.mutability {
+        return Err(Error::new_spanned(
+            mutability,
+            "game statics cannot be `mut`; write to them through the generated `GameStatic`",
+        ));
+    }
+
+    let ForeignItemStatic {
+        attrs,
+        vis,
+        ident,
+        ty,
+        ..
+    } = global;
+    let entry_name = ident.to_string();
+    let deref = args.deref.unwrap_or_else(|| syn::parse_quote!(0));
+    let address_block = address_block(args.address, args.module.as_ref(), &entry_name);
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis static #ident: ::re_utilities::game_static::GameStatic<#ty> =
+            ::re_utilities::game_static::GameStatic::new(#entry_name, #deref, || {
+                #address_block
+                Ok(address)
+            });
+    })
+}
+
+/// Lays out a reversed game structure from explicit field offsets, such as
+/// `#[game_struct(size = 0x200)] struct Player { #[offset(0x10)] health: f32 }`.
+///
+/// The struct becomes `#[repr(C)]`, with padding inserted before every field with an
+/// `#[offset(...)]`, and after the last field to reach `size`. Fields without an offset follow
+/// the previous field directly. The offsets and size are asserted at compile time, which also
+/// catches fields that are misaligned for their offset.
+///
+/// This is an attribute rather than a derive, as derives cannot change the struct's layout.
+#[proc_macro_attribute]
+pub fn game_struct(
+    args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let structure = parse_macro_input!(input as ItemStruct);
+    let result = Punctuated::<Expr, Token![,]>::parse_terminated
+        .parse(args)
+        .and_then(|args| expand_game_struct(args, structure));
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand_game_struct(
+    args: Punctuated<Expr, Token![,]>,
+    mut structure: ItemStruct,
+) -> Result<TokenStream> {
+    let mut size = None;
+    for arg in args {
+        match &arg {
+            Expr::Assign(ExprAssign { left, right, .. }) if matches!(left.as_ref(), Expr::Path(ExprPath { path, .. }) if path.is_ident("size")) =>
+            {
+                if size.is_some() {
+                    return Err(Error::new_spanned(left, "size has already been specified"));
+                }
+                size = Some(right.clone());
+            }
+            _ => return Err(Error::new_spanned(arg, "expected `size = ...`")),
+        }
+    }
+    if !structure.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &structure.generics,
+            "game structs cannot be generic",
+        ));
+    }
+    let Fields::Named(fields) = &mut structure.fields else {
+        return Err(Error::new_spanned(
+            &structure.fields,
+            "game structs must have named fields",
+        ));
     };
 
-    // Extract input
-    let detour = parse_macro_input!(input as ItemFn);
-    let visibility = detour.vis.clone();
-    let signature = detour.sig.clone();
-    let function_name = Ident::new(&signature.ident.to_string(), Span::call_site());
-    let detour_name = Ident::new(&function_name.to_string().to_uppercase(), Span::call_site());
-    let binder_name = Ident::new(&format!("{}_BINDER", detour_name), Span::call_site());
-    let detour_type = TypeBareFn {
-        lifetimes: None,
-        unsafety: signature.unsafety,
-        abi: signature.abi,
-        fn_token: signature.fn_token,
-        paren_token: signature.paren_token,
-        inputs: signature
-            .inputs
+    let name = &structure.ident;
+    let mut laid_out: Punctuated<Field, Token![,]> = Punctuated::new();
+    let mut assertions = vec![];
+    // The offset of the end of the previous field.
+    let mut end = quote! { 0 };
+    for (i, mut field) in std::mem::take(&mut fields.named).into_iter().enumerate() {
+        let offset = match field
+            .attrs
             .iter()
-            .filter_map(|arg| {
-                match arg {
-                    FnArg::Receiver(_) => {
-                        // Probably an error... cannot have a self type in a detour
-                        None
-                    }
-                    FnArg::Typed(typed) => Some(BareFnArg {
-                        attrs: typed.attrs.clone(),
-                        name: None,
-                        ty: *typed.ty.clone(),
-                    }),
-                }
-            })
-            .collect(),
-        variadic: signature.variadic.clone(),
-        output: signature.output.clone(),
+            .position(|attr| attr.path.is_ident("offset"))
+        {
+            Some(index) => {
+                let offset: Expr = field.attrs.remove(index).parse_args()?;
+                let padding = format_ident!("_padding{}", i);
+                laid_out.push(Field::parse_named.parse2(quote! {
+                    #padding: [u8; (#offset) - (#end)]
+                })?);
+                quote! { (#offset) }
+            }
+            None => end,
+        };
+
+        let field_name = field.ident.as_ref().expect("fields are named");
+        let message = LitStr::new(
+            &format!("`{}::{}` is not at its offset", name, field_name),
+            Span::call_site(),
+        );
+        assertions.push(quote! {
+            assert!(::core::mem::offset_of!(#name, #field_name) == #offset, #message);
+        });
+
+        let ty = &field.ty;
+        end = quote! { (#offset + ::core::mem::size_of::<#ty>()) };
+        laid_out.push(field);
+    }
+    if let Some(size) = &size {
+        laid_out.push(Field::parse_named.parse2(quote! {
+            _padding_end: [u8; (#size) - (#end)]
+        })?);
+        let message = LitStr::new(
+            &format!("`{}` is not the expected size", name),
+            Span::call_site(),
+        );
+        assertions.push(quote! {
+            assert!(::core::mem::size_of::<#name>() == #size, #message);
+        });
+    }
+    fields.named = laid_out;
+    structure.attrs.push(syn::parse_quote!(#[repr(C)]));
+
+    Ok(quote! {
+        #structure
+        const _: () = {
+            #(#assertions)*
+        };
+    })
+}
+
+/// Declares virtual methods of a game class, such as
+///
+/// ```ignore
+/// #[vtable]
+/// pub trait Entity {
+///     #[index(3)]
+///     extern "thiscall" fn update(&mut self, delta: f32);
+/// }
+/// ```
+///
+/// The trait becomes an `unsafe trait` whose methods call through the object's vtable; it
+/// should only be implemented for types whose first field is a pointer to the vtable. A
+/// module named after the trait (`entity_vtable`) holds, for each method, its index
+/// (`UPDATE`), the type of the function in the vtable with `this` as a raw pointer (`Update`),
+/// and a function to hook its slot (`hook_update`).
+#[proc_macro_attribute]
+pub fn vtable(
+    args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let declaration = parse_macro_input!(input as ItemTrait);
+    let result = if args.is_empty() {
+        expand_vtable(declaration)
+    } else {
+        Err(Error::new(
+            Span::call_site(),
+            "`#[vtable]` does not take arguments",
+        ))
     };
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
 
-    let address_block = match args.address {
-        Address::Signature(addr_sig) => {
-            let error_string = LitStr::new(
-                &format!("failed to find {}", signature.ident),
-                Span::call_site(),
-            );
-            quote! {
-                let address = module.scan(#addr_sig).map_err(|e| {
-                    match e {
-                        ::re_utilities::Error::PatternScanFailed { context } => {
-                            ::re_utilities::Error::PatternScanFailed {
-                                context: Some(format!(
-                                    "{}: {}",
-                                    #error_string,
-                                    context.unwrap_or_default()
-                                )),
-                            }
-                        }
-                        other => other,
+fn expand_vtable(mut declaration: ItemTrait) -> Result<TokenStream> {
+    let mut slots = vec![];
+    for item in &mut declaration.items {
+        let TraitItem::Method(method) = item else {
+            continue;
+        };
+        let Some(index) = method
+            .attrs
+            .iter()
+            .position(|attr| attr.path.is_ident("index"))
+        else {
+            continue;
+        };
+        let index: Expr = method.attrs.remove(index).parse_args()?;
+        if let Some(default) = &method.default {
+            return Err(Error::new_spanned(
+                default,
+                "virtual methods are called through the vtable, and cannot have a body",
+            ));
+        }
+
+        let signature = &mut method.sig;
+        let mut this = None;
+        let mut argument_names = vec![];
+        let mut argument_types = vec![];
+        for (i, arg) in signature.inputs.iter_mut().enumerate() {
+            match arg {
+                FnArg::Receiver(Receiver {
+                    reference: Some(_),
+                    mutability,
+                    ..
+                }) if i == 0 => {
+                    this = Some(match mutability {
+                        Some(_) => (
+                            quote! { *mut ::core::ffi::c_void },
+                            quote! { self as *mut Self as *mut ::core::ffi::c_void },
+                        ),
+                        None => (
+                            quote! { *const ::core::ffi::c_void },
+                            quote! { self as *const Self as *const ::core::ffi::c_void },
+                        ),
+                    });
+                }
+                FnArg::Receiver(_) => {
+                    return Err(Error::new_spanned(
+                        arg,
+                        "virtual methods must take `self` by reference",
+                    ))
+                }
+                FnArg::Typed(PatType { pat, ty, .. }) => {
+                    let name = match pat.as_ref() {
+                        Pat::Ident(PatIdent { ident, .. }) => ident.clone(),
+                        _ => format_ident!("argument{}", i),
+                    };
+                    **pat = syn::parse_quote!(#name);
+                    argument_names.push(name);
+                    argument_types.push(ty.clone());
+                }
+            }
+        }
+        let Some((this_type, this)) = this else {
+            return Err(Error::new_spanned(
+                &signature.ident,
+                "virtual methods must take `&self` or `&mut self`",
+            ));
+        };
+
+        // The method itself uses the Rust ABI; the function in the vtable uses the declared one.
+        let abi = signature.abi.take();
+        let unsafety = signature.unsafety;
+        let output = &signature.output;
+        let function_type = quote! {
+            #unsafety #abi fn(#this_type, #(#argument_types),*) #output
+        };
+        method.default = Some(syn::parse_quote! {{
+            unsafe {
+                let function: #function_type = ::core::mem::transmute(
+                    ::re_utilities::vtable::virtual_function(
+                        self as *const Self as *const ::core::ffi::c_void,
+                        #index,
+                    ),
+                );
+                function(#this, #(#argument_names),*)
+            }
+        }});
+        slots.push((signature.ident.clone(), index, function_type));
+    }
+    if slots.is_empty() {
+        return Err(Error::new(
+            Span::call_site(),
+            "no methods in the trait are marked with `#[index(...)]`",
+        ));
+    }
+
+    declaration.unsafety = Some(Default::default());
+    declaration.attrs.extend([
+        syn::parse_quote!(#[doc = ""]),
+        syn::parse_quote!(#[doc = " # Safety"]),
+        syn::parse_quote!(
+            #[doc = " Implementors must start with a pointer to a vtable with these methods."]
+        ),
+    ]);
+    declaration.supertraits.push(syn::parse_quote!(Sized));
+
+    let visibility = &declaration.vis;
+    let module_name = format_ident!(
+        "{}_vtable",
+        screaming_snake_case(&declaration.ident.to_string()).to_lowercase()
+    );
+    let slots = slots.into_iter().map(|(name, index, function_type)| {
+        let index_name = format_ident!("{}", name.to_string().to_uppercase());
+        let type_name = format_ident!("{}", camel_case(&name.to_string()));
+        let hook_name = format_ident!("hook_{}", name);
+        quote! {
+            pub const #index_name: usize = #index;
+            pub type #type_name = #function_type;
+
+            /// Creates a hook for this method's slot in the vtable at `vtable`.
+            ///
+            /// # Safety
+            /// `vtable` must point to a vtable for this trait.
+            pub unsafe fn #hook_name(
+                vtable: usize,
+                detour: #type_name,
+            ) -> ::re_utilities::vtable::VtableHook<#type_name> {
+                ::re_utilities::vtable::VtableHook::new(vtable, #index_name, detour)
+            }
+        }
+    });
+
+    Ok(quote! {
+        #declaration
+
+        #visibility mod #module_name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #(#slots)*
+        }
+    })
+}
+
+/// Gives a type a single, lazily created instance, with:
+/// - `create(...)`, which creates the instance with `new(...)` (returning
+///   `re_utilities::Result<Self>`), replacing any previous instance;
+/// - `get()`, which returns the instance as an `Arc`, if it exists;
+/// - `destroy(timeout)`, which removes the instance and drops it once no other thread is using
+///   it, or returns it if it is still in use after `timeout`.
+///
+/// See `re_utilities::singleton::Singleton` for the hazards of holding on to `get()`'s `Arc`.
+///
+/// The arguments of `create` are declared with `#[singleton(create(name: Type, ...))]`, and
+/// default to none. The type must be `Send + Sync`; use interior mutability for state that
+/// changes.
+#[proc_macro_derive(Singleton, attributes(singleton))]
+pub fn derive_singleton(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_singleton(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand_singleton(input: DeriveInput) -> Result<TokenStream> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "singletons cannot be generic",
+        ));
+    }
+
+    let mut arguments = Punctuated::<FnArg, Token![,]>::new();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("singleton"))
+    {
+        arguments = attr.parse_args_with(|input: ParseStream| {
+            let create: Ident = input.parse()?;
+            if create != "create" {
+                return Err(Error::new_spanned(create, "expected `create(...)`"));
+            }
+            let content;
+            parenthesized!(content in input);
+            Punctuated::parse_terminated(&content)
+        })?;
+    }
+    let mut argument_names = vec![];
+    for arg in &arguments {
+        match arg {
+            FnArg::Typed(PatType { pat, .. }) => match pat.as_ref() {
+                Pat::Ident(PatIdent { ident, .. }) => argument_names.push(ident),
+                _ => return Err(Error::new_spanned(pat, "expected an argument name")),
+            },
+            FnArg::Receiver(_) => {
+                return Err(Error::new_spanned(arg, "expected `name: Type`"));
+            }
+        }
+    }
+
+    let name = &input.ident;
+    Ok(quote! {
+        impl #name {
+            fn singleton() -> &'static ::re_utilities::singleton::Singleton<#name> {
+                static INSTANCE: ::re_utilities::singleton::Singleton<#name> =
+                    ::re_utilities::singleton::Singleton::new();
+                &INSTANCE
+            }
+
+            pub fn create(#arguments) -> ::re_utilities::Result<()> {
+                Self::singleton().set(Self::new(#(#argument_names),*)?);
+                Ok(())
+            }
+
+            pub fn destroy(
+                timeout: ::std::time::Duration,
+            ) -> ::std::result::Result<(), ::std::sync::Arc<#name>> {
+                Self::singleton().destroy(timeout)
+            }
+
+            #[allow(dead_code)]
+            pub fn get() -> Option<::std::sync::Arc<#name>> {
+                Self::singleton().get()
+            }
+        }
+    })
+}
+
+/// Collects every `#[detour]` in an inline module (and its inline submodules, whose detours must
+/// be visible to the module), generating:
+/// - `DETOURS`, a slice of their binders;
+/// - `hook_library()`, which builds a `HookLibrary` from every detour;
+/// - `hook_library_for_group(group)`, which builds one from the detours tagged with `group`.
+#[proc_macro_attribute]
+pub fn hook_library(
+    args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let module = parse_macro_input!(input as ItemMod);
+    let result = if args.is_empty() {
+        expand_hook_library(module)
+    } else {
+        Err(Error::new(
+            Span::call_site(),
+            "`#[hook_library]` does not take arguments",
+        ))
+    };
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand_hook_library(mut module: ItemMod) -> Result<TokenStream> {
+    let binders = collect_binders(&module, quote! {})?;
+    let (_, items) = module.content.as_mut().expect("checked by collect_binders");
+    items.push(syn::parse_quote! {
+        pub static DETOURS: &[&::re_utilities::detour_binder::CompiletimeDetourBinder] =
+            &[#(&#binders),*];
+    });
+    items.push(syn::parse_quote! {
+        pub fn hook_library() -> ::re_utilities::hook_library::HookLibrary {
+            DETOURS.iter().fold(
+                ::re_utilities::hook_library::HookLibrary::new(),
+                |library, binder| library.with_static_binder(*binder),
+            )
+        }
+    });
+    items.push(syn::parse_quote! {
+        pub fn hook_library_for_group(group: &str) -> ::re_utilities::hook_library::HookLibrary {
+            DETOURS
+                .iter()
+                .filter(|binder| binder.group == Some(group))
+                .fold(
+                    ::re_utilities::hook_library::HookLibrary::new(),
+                    |library, binder| library.with_static_binder(*binder),
+                )
+        }
+    });
+    Ok(quote! { #module })
+}
+
+/// Returns the paths (relative to the `#[hook_library]` module) of the binders of every detour
+/// in `module`, which is at `prefix`.
+fn collect_binders(module: &ItemMod, prefix: TokenStream) -> Result<Vec<TokenStream>> {
+    let Some((_, items)) = &module.content else {
+        return Err(Error::new_spanned(
+            module,
+            "`#[hook_library]` can only collect detours from inline modules",
+        ));
+    };
+
+    let mut binders = vec![];
+    for item in items {
+        let detours = match item {
+            Item::Fn(function) if function.attrs.iter().any(is_detour_attribute) => {
+                vec![detour_names(&function.sig.ident, None)?]
+            }
+            Item::Impl(detours) if detours.attrs.iter().any(is_detour_attribute) => detours
+                .items
+                .iter()
+                .filter_map(|item| match item {
+                    ImplItem::Method(method) if method.attrs.iter().any(is_detour_attribute) => {
+                        Some(detour_names(&method.sig.ident, Some(&detours.self_ty)))
                     }
+                    _ => None,
+                })
+                .collect::<Result<Vec<_>>>()?,
+            Item::Mod(submodule) if submodule.content.is_some() => {
+                let name = &submodule.ident;
+                binders.extend(collect_binders(submodule, quote! { #prefix #name:: })?);
+                continue;
+            }
+            _ => continue,
+        };
+        binders.extend(detours.into_iter().map(|(detour_name, _)| {
+            let binder_name = format_ident!("{}_BINDER", detour_name);
+            quote! { #prefix #binder_name }
+        }));
+    }
+    Ok(binders)
+}
+
+/// Expands every method marked with `#[detour(...)]` in an impl block.
+fn expand_impl(mut detours: ItemImpl) -> Result<TokenStream> {
+    if !detours.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &detours.generics,
+            "methods of generic impl blocks cannot be detoured",
+        ));
+    }
+
+    let mut statics = vec![];
+    for item in &mut detours.items {
+        let ImplItem::Method(method) = item else {
+            continue;
+        };
+        let Some(index) = method.attrs.iter().position(is_detour_attribute) else {
+            continue;
+        };
+        let args = method
+            .attrs
+            .remove(index)
+            .parse_args_with(Punctuated::<Expr, Token![,]>::parse_terminated)
+            .and_then(Args::new)?;
+        statics.push(expand_detour(
+            args,
+            &method.vis,
+            &method.sig,
+            &mut method.block,
+            Some(&detours.self_ty),
+        )?);
+    }
+    if statics.is_empty() {
+        return Err(Error::new(
+            Span::call_site(),
+            "no methods in the impl block are marked with `#[detour(...)]`",
+        ));
+    }
+
+    Ok(quote! {
+        #(#statics)*
+        #detours
+    })
+}
+
+/// Generates the statics for a detour, and makes `original(...)` available in its body.
+/// `self_type` is the type the function is implemented on, if it is a method.
+fn expand_detour(
+    args: Args,
+    visibility: &Visibility,
+    signature: &Signature,
+    block: &mut Block,
+    self_type: Option<&Type>,
+) -> Result<TokenStream> {
+    args.reject_game_static_arguments("#[detour]")?;
+    let function_name = &signature.ident;
+    let (detour_name, entry_name) = detour_names(function_name, self_type)?;
+    let function = match self_type {
+        Some(self_type) => quote! { <#self_type>::#function_name },
+        None => quote! { #function_name },
+    };
+    let binder_name = format_ident!("{}_BINDER", detour_name);
+
+    // The types of the arguments in the detour, in `original(...)`, and in the method itself.
+    // Receivers become a raw `this` pointer in the detour, and a reference elsewhere.
+    let mut detour_inputs = vec![];
+    let mut original_inputs = vec![];
+    let mut method_inputs = vec![];
+    let mut receiver = None;
+    for arg in &signature.inputs {
+        match arg {
+            FnArg::Receiver(Receiver {
+                reference: Some(_),
+                mutability,
+                ..
+            }) => {
+                let self_type = self_type.ok_or_else(|| {
+                    Error::new_spanned(
+                        arg,
+                        "methods must be detoured by putting `#[detour]` on their impl block",
+                    )
                 })?;
+                let pointer = match mutability {
+                    Some(_) => quote! { *mut #self_type },
+                    None => quote! { *const #self_type },
+                };
+                detour_inputs.push(pointer.clone());
+                original_inputs.push(quote! { &#mutability #self_type });
+                method_inputs.push(quote! { &#mutability #self_type });
+                receiver = Some(pointer);
+            }
+            FnArg::Receiver(_) => {
+                return Err(Error::new_spanned(
+                    arg,
+                    "detoured methods must take `self` by reference",
+                ));
+            }
+            FnArg::Typed(typed) => {
+                let ty = &typed.ty;
+                detour_inputs.push(quote! { #ty });
+                original_inputs.push(quote! { #ty });
+                method_inputs.push(quote! { #ty });
             }
         }
-        Address::Address(expr) => {
-            let expr = *expr;
-            quote! {
-                let address: usize = #expr;
+    }
+    let unsafety = signature.unsafety;
+    let abi = &signature.abi;
+    let variadic = signature
+        .variadic
+        .as_ref()
+        .map(|variadic| quote! { , #variadic });
+    let output = &signature.output;
+    let detour_type = quote! {
+        #unsafety #abi fn(#(#detour_inputs),* #variadic) #output
+    };
+    // The method's own function type differs from the detour's in its receiver, so it has to
+    // be transmuted.
+    let function = match receiver {
+        Some(_) => quote! {
+            ::std::mem::transmute::<#unsafety #abi fn(#(#method_inputs),*) #output, #detour_type>(
+                #function
+            )
+        },
+        None => function,
+    };
+
+    // Make `original(...)` available in the detour's body.
+    let argument_names: Vec<_> = (0..original_inputs.len())
+        .map(|i| format_ident!("argument{}", i))
+        .collect();
+    let call_arguments = argument_names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| match (&receiver, i) {
+            (Some(pointer), 0) => quote! { #name as #pointer },
+            _ => quote! { #name },
+        });
+    let unbound_message = LitStr::new(
+        &format!(
+            "`{}` called the original function before its detour was bound",
+            entry_name
+        ),
+        Span::call_site(),
+    );
+    let original: Stmt = syn::parse_quote! {
+        #[allow(dead_code)]
+        #unsafety fn original(#(#argument_names: #original_inputs),*) #output {
+            unsafe {
+                #detour_name
+                    .get()
+                    .expect(#unbound_message)
+                    .original()(#(#call_arguments),*)
+            }
+        }
+    };
+
+    // Catch panics in the body, and handle them according to the policy.
+    let mut saved_arguments = vec![];
+    let fallback = match args.panic.unwrap_or(PanicPolicy::Abort) {
+        PanicPolicy::CallOriginal => {
+            // The arguments are copied before the body runs, so that they can be passed on.
+            let mut arguments = vec![];
+            for (i, arg) in signature.inputs.iter().enumerate() {
+                let name = format_ident!("original_argument{}", i);
+                match arg {
+                    FnArg::Receiver(Receiver { mutability, .. }) => {
+                        let pointer = match mutability {
+                            Some(_) => quote! { *mut Self },
+                            None => quote! { *const Self },
+                        };
+                        saved_arguments.push(quote! { let #name = self as #pointer; });
+                        arguments.push(quote! { &#mutability *#name });
+                    }
+                    FnArg::Typed(PatType { pat, .. }) => {
+                        let Pat::Ident(PatIdent { ident, .. }) = pat.as_ref() else {
+                            return Err(Error::new_spanned(
+                                pat,
+                                "`panic = \"original\"` requires every argument to be named",
+                            ));
+                        };
+                        // Taken by reference, so that a non-`Copy` argument is reported as such
+                        // rather than as moved.
+                        saved_arguments.push(quote_spanned! {ident.span()=>
+                            let #name = ::re_utilities::detour_panic::save_argument(&#ident);
+                        });
+                        arguments.push(quote! { #name });
+                    }
+                }
             }
+            quote! {{
+                #[allow(unused_unsafe)]
+                let result = unsafe { original(#(#arguments),*) };
+                result
+            }}
         }
+        PanicPolicy::ReturnDefault => quote! { ::std::default::Default::default() },
+        PanicPolicy::Abort => quote! { ::std::process::abort() },
+    };
+    let body = &*block;
+    *block = syn::parse_quote! {{
+        #original
+        #(#saved_arguments)*
+        match ::re_utilities::detour_panic::catch(#entry_name, || #body) {
+            Some(result) => result,
+            None => #fallback,
+        }
+    }};
+
+    let address_block = address_block(args.address, args.module.as_ref(), &entry_name);
+
+    let group = match &args.group {
+        Some(group) => quote! { Some(#group) },
+        None => quote! { None },
     };
 
-    quote! {
-        #visibility static #detour_name: std::sync::OnceLock<::re_utilities::retour::GenericDetour<#detour_type>> = std::sync::OnceLock::new();
+    let engine = match &args.engine {
+        Some(engine) => quote! { #engine },
+        None => quote! { ::re_utilities::detour::DefaultEngine },
+    };
+    let priority = match &args.priority {
+        Some(priority) => quote! { #priority },
+        None => quote! { 0 },
+    };
+
+    Ok(quote! {
+        #visibility static #detour_name: std::sync::OnceLock<::re_utilities::detour::Detour<#detour_type, #engine>> = std::sync::OnceLock::new();
         #visibility static #binder_name: ::re_utilities::detour_binder::CompiletimeDetourBinder = ::re_utilities::detour_binder::CompiletimeDetourBinder {
+            name: #entry_name,
+            group: #group,
+            address: std::sync::OnceLock::new(),
             enable: &|| {
                 unsafe {
                     if #detour_name.get().is_none() {
                         #address_block
+                        #binder_name.address.set(address).ok();
                         #detour_name.set(
-                            ::re_utilities::retour::GenericDetour::<#detour_type>::new(
+                            ::re_utilities::detour::Detour::<#detour_type, #engine>::with_priority(
                                 ::std::mem::transmute(address),
-                                #function_name
+                                #function,
+                                #priority
                             )?
                         ).expect("detour already bound");
                     }
@@ -188,8 +1229,185 @@ pub fn detour(
                 Ok(())
             },
         };
+    })
+}
+
+/// Generates statements that find the address described by `address`, binding it to a `usize`
+/// named `address`, or return early with an error.
+fn address_block(address: Address, module: Option<&LitStr>, entry_name: &str) -> TokenStream {
+    let module = match module {
+        Some(module) => quote! { Some(#module) },
+        None => quote! { None },
+    };
+    match address {
+        Address::Absolute(expr) => quote! {
+            let address: usize = #expr;
+        },
+        source => {
+            let lookup = match source {
+                Address::Signatures(patterns) => {
+                    let (first, rest) = patterns.split_first().expect("at least one pattern");
+                    quote! {
+                        module.scan(#first)#(.or_else(|_| module.scan(#rest)))*
+                    }
+                }
+                Address::Callsite { pattern, offset } => quote! {
+                    module.scan_for_relative_callsite(#pattern, #offset)
+                },
+                Address::Rva(rva) => quote! {
+                    Ok(module.rel_to_abs_addr(#rva))
+                },
+                Address::Export(name) => quote! {
+                    module.export(#name)
+                },
+                Address::Absolute(_) => unreachable!(),
+            };
+            let error_string =
+                LitStr::new(&format!("failed to find {}", entry_name), Span::call_site());
+            quote! {
+                let address = ::re_utilities::module::Module::with_shared(#module, |module| {
+                    #lookup
+                })
+                .map_err(|e| {
+                    match e {
+                        ::re_utilities::Error::PatternScanFailed { context } => {
+                            ::re_utilities::Error::PatternScanFailed {
+                                context: Some(format!(
+                                    "{}: {}",
+                                    #error_string,
+                                    context.unwrap_or_default()
+                                )),
+                            }
+                        }
+                        other => other,
+                    }
+                })? as usize;
+            }
+        }
+    }
+}
+
+/// Returns the name of the detour's static, and the name of the detour itself.
+/// `self_type` is the type the function is implemented on, if it is a method.
+fn detour_names(function_name: &Ident, self_type: Option<&Type>) -> Result<(Ident, String)> {
+    let Some(self_type) = self_type else {
+        return Ok((
+            format_ident!("{}", function_name.to_string().to_uppercase()),
+            function_name.to_string(),
+        ));
+    };
+    let type_name = match self_type {
+        Type::Path(path) => path.path.segments.last().map(|segment| &segment.ident),
+        _ => None,
+    }
+    .ok_or_else(|| Error::new_spanned(self_type, "expected a type name"))?;
+    Ok((
+        format_ident!(
+            "{}_{}",
+            screaming_snake_case(&type_name.to_string()),
+            function_name.to_string().to_uppercase()
+        ),
+        format!("{}::{}", type_name, function_name),
+    ))
+}
+
+fn is_detour_attribute(attr: &Attribute) -> bool {
+    attr.path
+        .segments
+        .last()
+        .is_some_and(|segment| segment.ident == "detour")
+}
+
+/// Converts a `snake_case` function name to `CamelCase`.
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Converts a `CamelCase` type name to `SCREAMING_SNAKE_CASE`.
+fn screaming_snake_case(name: &str) -> String {
+    let mut result = String::new();
+    for (i, c) in name.char_indices() {
+        if i > 0 && c.is_uppercase() && !name[..i].ends_with(char::is_uppercase) {
+            result.push('_');
+        }
+        result.extend(c.to_uppercase());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Expands `#[detour(args)]` on `function`, with whitespace removed from the output.
+    fn expand(args: &str, function: &str) -> Result<String> {
+        let args = Punctuated::<Expr, Token![,]>::parse_terminated.parse_str(args)?;
+        let mut function: syn::ItemFn = syn::parse_str(function)?;
+        let statics = expand_detour(
+            Args::new(args)?,
+            &function.vis,
+            &function.sig,
+            &mut function.block,
+            None,
+        )?;
+        Ok(quote! { #statics #function }.to_string().replace(' ', ""))
+    }
+
+    const FUNCTION: &str = r#"extern "C" fn foo(a: i32, b: *const u8) -> i32 { a }"#;
+
+    #[test]
+    fn panics_abort_by_default() {
+        let expanded = expand("address = 0x1000", FUNCTION).unwrap();
+        assert!(expanded.contains("None=>::std::process::abort()"));
+        assert!(!expanded.contains("save_argument"));
+    }
+
+    #[test]
+    fn panics_return_the_default_value() {
+        let expanded = expand(r#"address = 0x1000, panic = "default""#, FUNCTION).unwrap();
+        assert!(expanded.contains("None=>::std::default::Default::default()"));
+        assert!(!expanded.contains("save_argument"));
+    }
+
+    #[test]
+    fn panics_call_the_original_with_copies_of_the_arguments() {
+        let expanded = expand(r#"address = 0x1000, panic = "original""#, FUNCTION).unwrap();
+        assert!(expanded.contains(
+            "letoriginal_argument0=::re_utilities::detour_panic::save_argument(&a);\
+             letoriginal_argument1=::re_utilities::detour_panic::save_argument(&b);"
+        ));
+        assert!(
+            expanded.contains("letresult=unsafe{original(original_argument0,original_argument1)};")
+        );
+    }
+
+    #[test]
+    fn calling_the_original_on_panic_requires_named_arguments() {
+        let error = expand(
+            r#"address = 0x1000, panic = "original""#,
+            r#"extern "C" fn foo(_: i32) {}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`panic = \"original\"` requires every argument to be named"
+        );
+    }
 
-        #detour
+    #[test]
+    fn unknown_panic_policies_are_rejected() {
+        let error = expand(r#"address = 0x1000, panic = "unwind""#, FUNCTION).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`panic` must be one of `original`, `default` or `abort`"
+        );
     }
-    .into()
 }
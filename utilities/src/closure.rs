@@ -0,0 +1,271 @@
+//! Thunks that let a boxed Rust closure stand in for a function of any calling convention.
+//!
+//! A [`ClosureThunk`] is generated from a [`MidHook`] whose callback records the thunk's state
+//! for the current thread, after which the thunk jumps to a handler compiled for the function
+//! type. As the callback leaves the registers and stack untouched, the handler receives the
+//! arguments as if it had been called directly; it then picks up the state and calls the
+//! closure with them.
+//!
+//! Code generation only depends on addresses, so it can be used on any platform; on Windows,
+//! [`ClosureDetour`](crate::detour::ClosureDetour) takes care of allocating and installing it.
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    function::{for_each_function_type, Function},
+    mid_hook::{self, MidHook},
+};
+
+/// The largest thunk that [`ClosureThunk::generate`] produces.
+pub const MAX_THUNK_LENGTH: usize = mid_hook::MAX_STUB_LENGTH;
+
+thread_local! {
+    /// The state of the thunk this thread has just entered, until its handler picks it up.
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A function pointer type that can be implemented by a closure.
+///
+/// The closure receives the original function, followed by the function's arguments: a
+/// closure for `extern "C" fn(i32) -> i32` has the type
+/// `dyn Fn(extern "C" fn(i32) -> i32, i32) -> i32 + Send + Sync`.
+///
+/// # Safety
+/// [`ClosureFunction::handler`] must return a function that calls the closure of the
+/// [`ClosureThunk`] that jumped to it.
+pub unsafe trait ClosureFunction: Function {
+    type Closure: ?Sized + Send + Sync;
+
+    /// The function that the thunks of this type jump to.
+    fn handler() -> Self;
+}
+
+struct State<F: ClosureFunction> {
+    closure: Box<F::Closure>,
+    original: Arc<AtomicUsize>,
+}
+
+impl<F: ClosureFunction> State<F> {
+    /// Returns the state of the thunk the current thread has just entered. Aborts the process
+    /// if there is none, as the handler has no closure to call and cannot unwind.
+    ///
+    /// # Safety
+    /// Must be called once by the handler for `F`, before anything that may enter a thunk.
+    unsafe fn current() -> &'static State<F> {
+        let state = CURRENT.with(|current| current.replace(0));
+        if state == 0 {
+            eprintln!("closure handler was called without going through its thunk; aborting");
+            std::process::abort();
+        }
+        &*(state as *const State<F>)
+    }
+
+    fn original(&self) -> F {
+        unsafe { F::from_ptr(self.original.load(Ordering::SeqCst) as *const ()) }
+    }
+}
+
+/// A closure and the code that calls it as a function of type `F`.
+///
+/// The generated thunk refers to the closure by address, so the `ClosureThunk` must outlive
+/// any code generated from it.
+pub struct ClosureThunk<F: ClosureFunction> {
+    state: Box<State<F>>,
+    hook: MidHook,
+}
+
+impl<F: ClosureFunction> ClosureThunk<F> {
+    /// Creates a thunk for `closure`, which is passed `original` when it is called.
+    pub fn new(closure: Box<F::Closure>, original: F) -> Self {
+        let state = Box::new(State {
+            closure,
+            original: Arc::new(AtomicUsize::new(original.to_ptr() as usize)),
+        });
+        let address = &*state as *const State<F> as usize;
+        let hook = MidHook::new(Arc::new(move |_| {
+            CURRENT.with(|current| current.set(address))
+        }));
+        ClosureThunk { state, hook }
+    }
+
+    /// Sets the function passed to the closure as the original, such as the trampoline of the
+    /// detour that the thunk is installed with.
+    pub fn set_original(&self, original: F) {
+        self.state
+            .original
+            .store(original.to_ptr() as usize, Ordering::SeqCst);
+    }
+
+    /// The address of the function passed to the closure as the original, which is read on
+    /// every call. It can be shared with whatever keeps track of the original, such as a hook
+    /// chain.
+    pub fn original_slot(&self) -> Arc<AtomicUsize> {
+        self.state.original.clone()
+    }
+
+    /// Generates the thunk to be placed at `stub_address`.
+    pub fn generate(&self, stub_address: usize) -> Vec<u8> {
+        self.hook
+            .generate_thunk(stub_address, F::handler().to_ptr() as usize)
+    }
+}
+
+macro_rules! impl_closure_function {
+    ($abi:tt $(#[$meta:meta])*; $($arg:ident),*) => {
+        $(#[$meta])*
+        unsafe impl<R: 'static, $($arg: 'static),*> ClosureFunction
+            for extern $abi fn($($arg),*) -> R
+        {
+            type Closure = dyn Fn(Self, $($arg),*) -> R + Send + Sync;
+
+            fn handler() -> Self {
+                #[allow(non_snake_case, clippy::too_many_arguments)]
+                extern $abi fn handler<R: 'static, $($arg: 'static),*>($($arg: $arg),*) -> R {
+                    let state = unsafe { State::<extern $abi fn($($arg),*) -> R>::current() };
+                    (state.closure)(state.original(), $($arg),*)
+                }
+                handler::<R, $($arg),*>
+            }
+        }
+
+        $(#[$meta])*
+        unsafe impl<R: 'static, $($arg: 'static),*> ClosureFunction
+            for unsafe extern $abi fn($($arg),*) -> R
+        {
+            type Closure = dyn Fn(Self, $($arg),*) -> R + Send + Sync;
+
+            fn handler() -> Self {
+                #[allow(non_snake_case, clippy::too_many_arguments)]
+                unsafe extern $abi fn handler<R: 'static, $($arg: 'static),*>(
+                    $($arg: $arg),*
+                ) -> R {
+                    let state = State::<unsafe extern $abi fn($($arg),*) -> R>::current();
+                    (state.closure)(state.original(), $($arg),*)
+                }
+                handler::<R, $($arg),*>
+            }
+        }
+    };
+}
+
+for_each_function_type!(impl_closure_function);
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_util::ExecutableMemory;
+
+    type Binary = extern "C" fn(u64, u64) -> u64;
+
+    extern "C" fn subtract(a: u64, b: u64) -> u64 {
+        a - b
+    }
+
+    /// Generates `thunk` into `memory` at `offset` and returns it as a function.
+    fn install<F: ClosureFunction>(
+        memory: &ExecutableMemory,
+        offset: usize,
+        thunk: &ClosureThunk<F>,
+    ) -> F {
+        let code = thunk.generate(memory.address() + offset);
+        assert!(code.len() <= MAX_THUNK_LENGTH);
+        memory.write(offset, &code);
+        unsafe { memory.function(offset) }
+    }
+
+    #[test]
+    fn calls_closure_with_state_and_original() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let captured = calls.clone();
+        let thunk = ClosureThunk::<Binary>::new(
+            Box::new(move |original, a, b| {
+                captured.fetch_add(1, Ordering::SeqCst);
+                original(a, b) * 10
+            }),
+            subtract,
+        );
+        let memory = ExecutableMemory::new(4096);
+        let function = install(&memory, 0, &thunk);
+
+        assert_eq!(function(7, 2), 50);
+        assert_eq!(function(100, 1), 990);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        extern "C" fn add(a: u64, b: u64) -> u64 {
+            a + b
+        }
+        thunk.set_original(add);
+        assert_eq!(function(7, 2), 90);
+    }
+
+    #[test]
+    fn passes_every_argument() {
+        type Many = extern "C" fn(u64, u64, u64, u64, u64, u64, u64, u64) -> u64;
+        extern "C" fn unused(
+            _: u64,
+            _: u64,
+            _: u64,
+            _: u64,
+            _: u64,
+            _: u64,
+            _: u64,
+            _: u64,
+        ) -> u64 {
+            unreachable!()
+        }
+        let thunk = ClosureThunk::<Many>::new(
+            Box::new(|_, a, b, c, d, e, f, g, h| {
+                [a, b, c, d, e, f, g, h]
+                    .iter()
+                    .enumerate()
+                    .map(|(index, value)| value << (index * 8))
+                    .sum()
+            }),
+            unused,
+        );
+        let memory = ExecutableMemory::new(4096);
+        let function = install(&memory, 0, &thunk);
+
+        assert_eq!(function(1, 2, 3, 4, 5, 6, 7, 8), 0x0807_0605_0403_0201);
+    }
+
+    #[test]
+    fn nested_and_reentrant_calls() {
+        let memory = ExecutableMemory::new(4096);
+
+        // The inner thunk calls back into itself to compute a factorial.
+        let inner_address = Arc::new(AtomicUsize::new(0));
+        let captured = inner_address.clone();
+        let inner = ClosureThunk::<Binary>::new(
+            Box::new(move |original, n, _| {
+                if n <= 1 {
+                    return 1;
+                }
+                let itself: Binary =
+                    unsafe { Binary::from_ptr(captured.load(Ordering::SeqCst) as _) };
+                n * itself(original(n, 1), 0)
+            }),
+            subtract,
+        );
+        let inner_function = install(&memory, 0, &inner);
+        inner_address.store(inner_function as usize, Ordering::SeqCst);
+
+        // The outer thunk calls the inner one between picking up its state and using it.
+        let outer = ClosureThunk::<Binary>::new(
+            Box::new(move |original, a, b| inner_function(a, 0) + original(a, b)),
+            subtract,
+        );
+        let outer_function = install(&memory, 2048, &outer);
+
+        assert_eq!(inner_function(5, 0), 120);
+        assert_eq!(outer_function(4, 1), 24 + 3);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    panic::{self, AssertUnwindSafe},
+    sync::{Once, RwLock},
+};
+
+type Reporter = Box<dyn Fn(&DetourPanic) + Send + Sync>;
+
+static REPORTER: RwLock<Option<Reporter>> = RwLock::new(None);
+static INSTALL_HOOK: Once = Once::new();
+
+thread_local! {
+    /// How many detours are catching panics on this thread.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// The location of the last panic caught in a detour on this thread.
+    static LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A panic caught in a detour.
+#[derive(Debug)]
+pub struct DetourPanic<'a> {
+    /// The name of the detour that panicked.
+    pub detour: &'a str,
+    /// The panic message, if it was a string.
+    pub message: Option<&'a str>,
+    /// Where the panic occurred, as `file:line:column`.
+    pub location: Option<&'a str>,
+}
+
+/// Sets the function that is called when a detour panics, replacing the default of printing
+/// the panic to stderr.
+///
+/// Panics in detours are not passed to the panic hook, as they are handled by the detour.
+pub fn set_reporter(reporter: impl Fn(&DetourPanic) + Send + Sync + 'static) {
+    *REPORTER.write().unwrap() = Some(Box::new(reporter));
+}
+
+/// Runs the body of the detour `detour`, catching any panic so that it does not unwind into
+/// the caller. Returns `None` if the body panicked, after reporting the panic.
+#[doc(hidden)]
+pub fn catch<R>(detour: &str, body: impl FnOnce() -> R) -> Option<R> {
+    INSTALL_HOOK.call_once(install_hook);
+
+    DEPTH.with(|depth| depth.set(depth.get() + 1));
+    let result = panic::catch_unwind(AssertUnwindSafe(body));
+    DEPTH.with(|depth| depth.set(depth.get() - 1));
+
+    result.map_err(|payload| report(detour, payload)).ok()
+}
+
+/// The types of arguments that a detour with `panic = "original"` can pass on to the original
+/// function. They are copied before the body runs, so they must be `Copy`.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "`panic = \"original\"` requires every argument to be `Copy`, but `{Self}` is not",
+    label = "this argument is not `Copy`"
+)]
+pub trait OriginalArgument: Copy {}
+
+impl<T: Copy> OriginalArgument for T {}
+
+/// Copies an argument of a detour with `panic = "original"`, so that it can be passed on to
+/// the original function if the body panics.
+#[doc(hidden)]
+pub fn save_argument<T: OriginalArgument>(argument: &T) -> T {
+    *argument
+}
+
+/// Records the location of panics inside detours, and passes other panics to the previous hook.
+fn install_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if DEPTH.with(Cell::get) > 0 {
+            let location = info.location().map(ToString::to_string);
+            LOCATION.with(|last| *last.borrow_mut() = location);
+        } else {
+            previous(info);
+        }
+    }));
+}
+
+fn report(detour: &str, payload: Box<dyn Any + Send>) {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str));
+    let location = LOCATION.with(|location| location.borrow_mut().take());
+    let panic = DetourPanic {
+        detour,
+        message,
+        location: location.as_deref(),
+    };
+
+    match REPORTER.read().unwrap().as_ref() {
+        Some(reporter) => reporter(&panic),
+        None => eprintln!(
+            "detour `{}` panicked at {}: {}",
+            panic.detour,
+            panic.location.unwrap_or("unknown location"),
+            panic.message.unwrap_or("Box<dyn Any>")
+        ),
+    }
+}
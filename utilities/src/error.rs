@@ -8,6 +8,13 @@ pub enum WindowsError {
     ThreadSnapshotFailed { source: windows::core::Error },
     /// Failed to open a thread
     ThreadOpenFailed { source: windows::core::Error },
+    /// Failed to get or set the context of a thread
+    ThreadContextFailed { source: windows::core::Error },
+    /// No loaded module has the given name
+    ModuleNotFound {
+        name: String,
+        source: windows::core::Error,
+    },
 }
 
 #[cfg(target_os = "windows")]
@@ -20,6 +27,12 @@ impl fmt::Display for WindowsError {
             WindowsError::ThreadOpenFailed { source } => {
                 write!(f, "failed to open thread: {}", source)
             }
+            WindowsError::ThreadContextFailed { source } => {
+                write!(f, "failed to access thread context: {}", source)
+            }
+            WindowsError::ModuleNotFound { name, source } => {
+                write!(f, "failed to find module {}: {}", name, source)
+            }
         }
     }
 }
@@ -30,6 +43,8 @@ impl std::error::Error for WindowsError {
         match self {
             WindowsError::ThreadSnapshotFailed { source } => Some(source),
             WindowsError::ThreadOpenFailed { source } => Some(source),
+            WindowsError::ThreadContextFailed { source } => Some(source),
+            WindowsError::ModuleNotFound { source, .. } => Some(source),
         }
     }
 }
@@ -43,8 +58,39 @@ pub enum Error {
     ModulePathUnavailable,
     /// Failed to unpatch at the given address
     UnpatchFailed { address: usize },
+    /// Failed to allocate memory within rel32 range of the given address
+    NearAllocationFailed { address: usize },
+    /// Failed to decode the instruction at the given address
+    InstructionDecodeFailed { address: usize },
+    /// The instruction at the given address is not of the expected kind
+    UnexpectedInstruction {
+        address: usize,
+        expected: &'static str,
+    },
+    /// The target cannot be encoded in the instruction at the given address
+    TargetOutOfRange { address: usize, target: usize },
+    /// A branch targets the middle of instructions that are being relocated
+    BranchIntoRelocatedCode { address: usize, target: usize },
+    /// The code generated for the hook at the given address does not fit in the space reserved
+    /// for it
+    GeneratedCodeTooLong {
+        address: usize,
+        length: usize,
+        max_length: usize,
+    },
+    /// A suspended thread did not leave a range that is being patched
+    ThreadInPatchedRange { address: usize },
+    /// The range cannot be written with a single atomic operation
+    AtomicWriteUnsupported { address: usize, length: usize },
+    /// A mid hook cannot be placed on code that is already patched
+    AddressAlreadyPatched { address: usize },
+    /// The module does not export a function with the given name
+    ExportNotFound {
+        module: Option<String>,
+        name: String,
+    },
     /// Detour operation failed
-    #[cfg(target_os = "windows")]
+    #[cfg(all(target_os = "windows", feature = "retour"))]
     DetourFailed { source: retour::Error },
     /// I/O operation failed
     Io {
@@ -59,6 +105,9 @@ pub enum Error {
     },
     /// Integer conversion failed
     IntConversion { source: std::num::TryFromIntError },
+    /// Patch manifest could not be parsed
+    #[cfg(feature = "manifest")]
+    ManifestParse { source: toml::de::Error },
     /// Windows-specific error
     #[cfg(target_os = "windows")]
     Windows(WindowsError),
@@ -80,7 +129,79 @@ impl fmt::Display for Error {
             Error::UnpatchFailed { address } => {
                 write!(f, "failed to unpatch at address 0x{:x}", address)
             }
-            #[cfg(target_os = "windows")]
+            Error::NearAllocationFailed { address } => {
+                write!(
+                    f,
+                    "failed to allocate memory within rel32 range of address 0x{:x}",
+                    address
+                )
+            }
+            Error::InstructionDecodeFailed { address } => {
+                write!(f, "failed to decode instruction at address 0x{:x}", address)
+            }
+            Error::UnexpectedInstruction { address, expected } => {
+                write!(
+                    f,
+                    "unexpected instruction at address 0x{:x}: expected {}",
+                    address, expected
+                )
+            }
+            Error::TargetOutOfRange { address, target } => {
+                write!(
+                    f,
+                    "target 0x{:x} is out of range of the instruction at address 0x{:x}",
+                    target, address
+                )
+            }
+            Error::BranchIntoRelocatedCode { address, target } => {
+                write!(
+                    f,
+                    "the branch at address 0x{:x} targets relocated code at 0x{:x}",
+                    address, target
+                )
+            }
+            Error::GeneratedCodeTooLong {
+                address,
+                length,
+                max_length,
+            } => {
+                write!(
+                    f,
+                    "the code generated for the hook at address 0x{:x} is {} bytes long, \
+                     more than the {} bytes reserved for it",
+                    address, length, max_length
+                )
+            }
+            Error::ThreadInPatchedRange { address } => {
+                write!(
+                    f,
+                    "a thread is executing at address 0x{:x}, inside the range being patched",
+                    address
+                )
+            }
+            Error::AtomicWriteUnsupported { address, length } => {
+                write!(
+                    f,
+                    "{} bytes at address 0x{:x} cannot be written atomically",
+                    length, address
+                )
+            }
+            Error::AddressAlreadyPatched { address } => {
+                write!(
+                    f,
+                    "address 0x{:x} is already patched and cannot be mid-hooked",
+                    address
+                )
+            }
+            Error::ExportNotFound { module, name } => {
+                write!(
+                    f,
+                    "{} does not export {}",
+                    module.as_deref().unwrap_or("module"),
+                    name
+                )
+            }
+            #[cfg(all(target_os = "windows", feature = "retour"))]
             Error::DetourFailed { source } => {
                 write!(f, "detour operation failed: {}", source)
             }
@@ -100,6 +221,10 @@ impl fmt::Display for Error {
             Error::IntConversion { source } => {
                 write!(f, "integer conversion failed: {}", source)
             }
+            #[cfg(feature = "manifest")]
+            Error::ManifestParse { source } => {
+                write!(f, "failed to parse patch manifest: {}", source)
+            }
             #[cfg(target_os = "windows")]
             Error::Windows(e) => write!(f, "{}", e),
         }
@@ -109,12 +234,14 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            #[cfg(target_os = "windows")]
+            #[cfg(all(target_os = "windows", feature = "retour"))]
             Error::DetourFailed { source } => Some(source),
             Error::Io { source, .. } => Some(source),
             Error::PatternScan { source } => Some(source),
             Error::ArrayConversion { source } => Some(source),
             Error::IntConversion { source } => Some(source),
+            #[cfg(feature = "manifest")]
+            Error::ManifestParse { source } => Some(source),
             #[cfg(target_os = "windows")]
             Error::Windows(e) => e.source(),
             _ => None,
@@ -122,7 +249,7 @@ impl std::error::Error for Error {
     }
 }
 
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "windows", feature = "retour"))]
 impl From<retour::Error> for Error {
     fn from(source: retour::Error) -> Self {
         Error::DetourFailed { source }
@@ -0,0 +1,73 @@
+/// A function pointer type that can be hooked.
+///
+/// Implemented for `fn` and `unsafe fn` pointers of up to 14 arguments, in every calling
+/// convention supported on the target (`thiscall`, `stdcall` and `fastcall` on x86, `win64`
+/// and `sysv64` on x86-64).
+///
+/// # Safety
+/// Implementors must be pointer-sized function pointers, so that converting to and from an
+/// address is lossless.
+pub unsafe trait Function: Sized + Copy + Send + Sync + 'static {
+    /// Converts an address into the function pointer.
+    ///
+    /// # Safety
+    /// `ptr` must point to a function of this type.
+    unsafe fn from_ptr(ptr: *const ()) -> Self;
+
+    /// The address of the function.
+    fn to_ptr(&self) -> *const ();
+}
+
+/// Invokes `$callback!(abi #[cfg(...)]; A, B, ...)` for every calling convention supported on
+/// the target (with the `cfg` it requires, if any), and every argument count up to 14.
+macro_rules! for_each_function_type {
+    ($callback:ident) => {
+        for_each_function_type!(@arity $callback; A, B, C, D, E, F, G, H, I, J, K, L, M, N);
+    };
+    (@arity $callback:ident;) => {
+        for_each_function_type!(@abi $callback;);
+    };
+    (@arity $callback:ident; $first:ident $(, $rest:ident)*) => {
+        for_each_function_type!(@abi $callback; $first $(, $rest)*);
+        for_each_function_type!(@arity $callback; $($rest),*);
+    };
+    (@abi $callback:ident; $($arg:ident),*) => {
+        $callback!("Rust"; $($arg),*);
+        $callback!("C"; $($arg),*);
+        $callback!("system"; $($arg),*);
+        $callback!("thiscall" #[cfg(target_arch = "x86")]; $($arg),*);
+        $callback!("stdcall" #[cfg(target_arch = "x86")]; $($arg),*);
+        $callback!("fastcall" #[cfg(target_arch = "x86")]; $($arg),*);
+        $callback!("win64" #[cfg(target_arch = "x86_64")]; $($arg),*);
+        $callback!("sysv64" #[cfg(target_arch = "x86_64")]; $($arg),*);
+    };
+}
+pub(crate) use for_each_function_type;
+
+macro_rules! impl_function {
+    ($abi:tt $(#[$meta:meta])*; $($arg:ident),*) => {
+        $(#[$meta])*
+        unsafe impl<R: 'static, $($arg: 'static),*> Function for extern $abi fn($($arg),*) -> R {
+            unsafe fn from_ptr(ptr: *const ()) -> Self {
+                std::mem::transmute(ptr)
+            }
+            fn to_ptr(&self) -> *const () {
+                *self as *const ()
+            }
+        }
+
+        $(#[$meta])*
+        unsafe impl<R: 'static, $($arg: 'static),*> Function
+            for unsafe extern $abi fn($($arg),*) -> R
+        {
+            unsafe fn from_ptr(ptr: *const ()) -> Self {
+                std::mem::transmute(ptr)
+            }
+            fn to_ptr(&self) -> *const () {
+                *self as *const ()
+            }
+        }
+    };
+}
+
+for_each_function_type!(impl_function);
@@ -0,0 +1,36 @@
+use crate::error::Result;
+
+/// A backend that installs inline hooks, redirecting calls to a function to a detour.
+///
+/// Typed hooks are created through [`Detour`](crate::detour::Detour), which is generic over
+/// the engine, so that `#[detour(engine = ...)]` and the hook library can be used with any
+/// implementation of this trait. Each function is only detoured once, by the engine of the
+/// first hook attached to it; see [`ChainedHook`](crate::ChainedHook).
+pub trait HookEngine: Sized + Send + Sync + 'static {
+    /// Prepares a hook that redirects `target` to `detour`. The hook starts disabled.
+    ///
+    /// # Safety
+    /// `target` and `detour` must be functions with the same signature, and `target` must be
+    /// long enough to be overwritten by a jump.
+    unsafe fn new(target: *const (), detour: *const ()) -> Result<Self>;
+
+    /// Redirects the target to the detour.
+    ///
+    /// # Safety
+    /// No thread may be executing the start of the target while it is being overwritten.
+    unsafe fn enable(&self) -> Result<()>;
+
+    /// Restores the target.
+    ///
+    /// # Safety
+    /// No thread may be executing the start of the target while it is being restored.
+    unsafe fn disable(&self) -> Result<()>;
+
+    fn is_enabled(&self) -> bool;
+
+    /// A function that behaves like the target did before it was hooked.
+    fn trampoline(&self) -> *const ();
+
+    /// The hooked function.
+    fn target(&self) -> *const ();
+}
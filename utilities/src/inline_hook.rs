@@ -0,0 +1,213 @@
+//! Inline hooks that redirect a function to a detour, with a trampoline to call the original.
+//!
+//! The start of the target is overwritten with a jump to the detour: a `jmp rel32` if the
+//! detour is within range, or a 14-byte absolute jump otherwise. The overwritten instructions
+//! are relocated into the trampoline, followed by a jump back to the rest of the target.
+//! Neither jump touches registers or the stack, so functions of any calling convention
+//! (including `thiscall`) can be hooked.
+//!
+//! Code generation only depends on addresses, so it can be used on any platform; on Windows,
+//! [`InlineEngine`](crate::detour::InlineEngine) takes care of allocating and installing it.
+
+use crate::{
+    error::{Error, Result},
+    x86::{self, Mode},
+};
+
+/// The largest trampoline that [`generate`] produces, as relocated branches may be widened.
+pub const MAX_TRAMPOLINE_LENGTH: usize = 256;
+
+/// The most bytes at the target that may be needed by [`generate`]: the jump may end partway
+/// through an instruction, which is then relocated whole.
+pub const MAX_OVERWRITTEN_LENGTH: usize = MAX_JUMP_LENGTH + x86::MAX_INSTRUCTION_LENGTH - 1;
+
+/// The length of the absolute jump used when the detour is out of rel32 range.
+const MAX_JUMP_LENGTH: usize = 14;
+
+/// The code generated for an inline hook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlineHookCode {
+    /// The trampoline to place at the trampoline address.
+    pub trampoline: Vec<u8>,
+    /// The jump to the detour that replaces the start of the target, padded with NOPs to the
+    /// end of the last overwritten instruction.
+    pub entry: Vec<u8>,
+    /// Where execution should continue for each overwritten instruction, as pairs of
+    /// addresses. A thread about to execute the first instruction stays there, so that it runs
+    /// the detour.
+    pub instruction_map: Vec<(usize, usize)>,
+}
+
+/// Generates the code to detour `target` to `detour`, where `code` holds the original bytes at
+/// `target` (at least [`MAX_OVERWRITTEN_LENGTH`] of them, if available) and the trampoline
+/// will be placed at `trampoline_address`.
+///
+/// RIP-relative operands in the overwritten instructions cannot be widened, so the trampoline
+/// should be placed within rel32 range of `target`.
+pub fn generate(
+    target: usize,
+    code: &[u8],
+    trampoline_address: usize,
+    detour: usize,
+) -> Result<InlineHookCode> {
+    let mut entry = x86::jump(target, detour, Mode::NATIVE);
+
+    let relocation = x86::relocate(code, target, trampoline_address, entry.len(), Mode::NATIVE)?;
+    let instruction_map = relocation
+        .offsets
+        .iter()
+        .map(|(source, relocated)| match source {
+            0 => (target, target),
+            _ => (target + source, trampoline_address + relocated),
+        })
+        .collect();
+
+    let mut trampoline = relocation.bytes;
+    trampoline.extend(x86::jump(
+        trampoline_address + trampoline.len(),
+        target + relocation.source_length,
+        Mode::NATIVE,
+    ));
+    if trampoline.len() > MAX_TRAMPOLINE_LENGTH {
+        return Err(Error::GeneratedCodeTooLong {
+            address: target,
+            length: trampoline.len(),
+            max_length: MAX_TRAMPOLINE_LENGTH,
+        });
+    }
+
+    entry.resize(relocation.source_length, 0x90);
+
+    Ok(InlineHookCode {
+        trampoline,
+        entry,
+        instruction_map,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::test_util::ExecutableMemory;
+
+    type TestFunction = unsafe extern "sysv64" fn(i32) -> i32;
+
+    thread_local! {
+        static TRAMPOLINE: Cell<usize> = const { Cell::new(0) };
+    }
+
+    extern "sysv64" fn detour(x: i32) -> i32 {
+        let original: TestFunction = unsafe { std::mem::transmute(TRAMPOLINE.get()) };
+        unsafe { original(x) + 1000 }
+    }
+
+    const TRAMPOLINE_OFFSET: usize = 0x1000;
+    const DETOUR_STUB_OFFSET: usize = 0x2000;
+
+    /// Hooks the function at the start of `memory`. If `near` is set, the detour is reached
+    /// through a stub within rel32 range, so that the entry is a `jmp rel32`.
+    fn hook(memory: &ExecutableMemory, near: bool) -> Result<InlineHookCode> {
+        let target = memory.address();
+        let detour = detour as *const () as usize;
+        let detour = if near {
+            // jmp qword ptr [rip+0]; dq detour
+            let stub = [
+                &[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00][..],
+                &(detour as u64).to_le_bytes(),
+            ]
+            .concat();
+            memory.write(DETOUR_STUB_OFFSET, &stub)
+        } else {
+            detour
+        };
+        let trampoline = memory.address() + TRAMPOLINE_OFFSET;
+        let code = &memory.bytes(0)[..MAX_OVERWRITTEN_LENGTH];
+
+        let generated = generate(target, code, trampoline, detour)?;
+        assert!(generated.trampoline.len() <= MAX_TRAMPOLINE_LENGTH);
+        assert_eq!(generated.instruction_map[0], (target, target));
+        memory.write(TRAMPOLINE_OFFSET, &generated.trampoline);
+        memory.write(0, &generated.entry);
+        TRAMPOLINE.set(trampoline);
+        Ok(generated)
+    }
+
+    /// Hooks `code` with both a near and a far entry, checking that the detour is called and
+    /// that the trampoline behaves like the original function for each of `inputs`.
+    fn check(code: &[u8], inputs: &[i32]) {
+        for near in [true, false] {
+            let memory = ExecutableMemory::new(0x3000);
+            memory.write(0, code);
+            let function: TestFunction = unsafe { memory.function(0) };
+            let expected: Vec<i32> = inputs.iter().map(|x| unsafe { function(*x) }).collect();
+
+            let generated = hook(&memory, near).unwrap();
+            if near {
+                assert_eq!(generated.entry[0], 0xE9);
+            }
+            let trampoline: TestFunction = unsafe { memory.function(TRAMPOLINE_OFFSET) };
+            for (x, expected) in inputs.iter().zip(expected) {
+                assert_eq!(unsafe { function(*x) }, expected + 1000, "near: {near}");
+                assert_eq!(unsafe { trampoline(*x) }, expected, "near: {near}");
+            }
+        }
+    }
+
+    #[test]
+    fn rip_relative_prologue() {
+        let mut code = vec![];
+        // mov eax, [rip+data]; add eax, edi; add eax, 1 (x3); ret
+        code.extend([0x8B, 0x05, 0x0E, 0x00, 0x00, 0x00, 0x01, 0xF8]);
+        code.extend([0x83, 0xC0, 0x01].repeat(3));
+        code.extend([0xC3, 0xCC, 0xCC]);
+        // data: dd 100
+        code.extend(100_i32.to_le_bytes());
+        check(&code, &[0, 5, -7]);
+    }
+
+    #[test]
+    fn conditional_jump_in_prologue() {
+        let mut code = vec![];
+        // test edi, edi; jz skip; lea eax, [rdi+1]; imul eax, eax, 3; add eax, 5 (x2); ret
+        code.extend([0x85, 0xFF, 0x74, 0x0D, 0x8D, 0x47, 0x01, 0x6B, 0xC0, 0x03]);
+        code.extend([0x83, 0xC0, 0x05].repeat(2));
+        code.push(0xC3);
+        // skip: mov eax, -1; ret
+        code.extend([0xB8, 0xFF, 0xFF, 0xFF, 0xFF, 0xC3]);
+        check(&code, &[0, 5]);
+    }
+
+    #[test]
+    fn call_in_prologue() {
+        let mut code = vec![];
+        // call helper; add eax, edi; add eax, 1 (x3); ret
+        code.extend([0xE8, 0x13, 0x00, 0x00, 0x00, 0x01, 0xF8]);
+        code.extend([0x83, 0xC0, 0x01].repeat(3));
+        code.push(0xC3);
+        code.resize(24, 0xCC);
+        // helper: mov eax, 40; ret
+        code.extend([0xB8, 0x28, 0x00, 0x00, 0x00, 0xC3]);
+        check(&code, &[0, 5]);
+    }
+
+    #[test]
+    fn function_shorter_than_entry() {
+        for near in [true, false] {
+            let memory = ExecutableMemory::new(0x3000);
+            // lea eax, [rdi+1]; ret
+            let code = [0x8D, 0x47, 0x01, 0xC3];
+            memory.write(0, &code);
+
+            let error = hook(&memory, near).unwrap_err();
+            assert!(matches!(
+                error,
+                Error::UnexpectedInstruction { address, .. } if address == memory.address() + 3
+            ));
+            assert_eq!(&memory.bytes(0)[..code.len()], code);
+            let function: TestFunction = unsafe { memory.function(0) };
+            assert_eq!(unsafe { function(5) }, 6);
+        }
+    }
+}
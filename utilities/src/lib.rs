@@ -1,5 +1,21 @@
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod closure;
+pub mod detour_panic;
 pub mod error;
+pub mod function;
+pub mod hook_engine;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod inline_hook;
+#[cfg(feature = "manifest")]
+pub mod manifest;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod mid_hook;
+pub mod singleton;
 pub mod util;
+pub mod x86;
+
+#[cfg(test)]
+mod test_util;
 
 #[cfg(target_os = "windows")]
 mod windows;
@@ -7,7 +23,9 @@ mod windows;
 #[cfg(target_os = "windows")]
 pub use crate::windows::*;
 
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "windows", feature = "retour"))]
 pub use retour;
 
 pub use error::{Error, Result, UserCallbackResult};
+pub use function::Function;
+pub use hook_engine::HookEngine;
@@ -0,0 +1,584 @@
+//! Declarative byte patches loaded from a TOML manifest at runtime.
+//!
+//! ```toml
+//! [[patch]]
+//! name = "Uncap framerate"
+//! module = "engine.dll"              # optional, defaults to the main executable
+//! pattern = "F3 0F 10 05 ? ? ? ? 0F 2F C1"   # or `rva = 0x1234`
+//! offset = 4
+//! expected = "00 00 70 42"           # optional
+//! replacement = "00 00 F0 42"
+//! enabled = true                     # optional, defaults to true
+//! ```
+//!
+//! Parsing and resolution only depend on an [`Image`], so they work the same against loaded
+//! modules and plain buffers.
+
+use std::{fmt, io};
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+
+/// A loaded image that manifest entries can be resolved against.
+pub trait Image {
+    /// The address the image is loaded at.
+    fn base(&self) -> usize;
+    /// The contents of the image.
+    fn bytes(&self) -> &[u8];
+}
+
+/// An [`Image`] backed by a byte buffer.
+pub struct BufferImage<'a> {
+    pub base: usize,
+    pub bytes: &'a [u8],
+}
+
+impl Image for BufferImage<'_> {
+    fn base(&self) -> usize {
+        self.base
+    }
+    fn bytes(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+/// A list of named byte patches.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    #[serde(rename = "patch", default)]
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A single named patch in a [`Manifest`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub name: String,
+    /// The module to patch, or the main executable if not specified.
+    pub module: Option<String>,
+    /// A pattern locating the patch, which must match exactly once; mutually exclusive with
+    /// `rva`.
+    pub pattern: Option<String>,
+    /// An offset from the module base locating the patch; mutually exclusive with `pattern`.
+    pub rva: Option<usize>,
+    /// Added to the location found through `pattern` or `rva`.
+    #[serde(default)]
+    pub offset: isize,
+    /// The bytes that must be present before patching, if specified.
+    pub expected: Option<HexBytes>,
+    pub replacement: HexBytes,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Bytes written as space-separated hex pairs (`"DE AD BE EF"`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HexBytes(pub Vec<u8>);
+
+impl TryFrom<String> for HexBytes {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value
+            .split_whitespace()
+            .map(|byte| {
+                u8::from_str_radix(byte, 16)
+                    .ok()
+                    .filter(|_| byte.len() == 2)
+                    .ok_or_else(|| format!("`{}` is not a hex byte", byte))
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map(HexBytes)
+    }
+}
+
+impl fmt::Display for HexBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if index != 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// A manifest entry that has been located in memory.
+#[derive(Debug, Clone)]
+pub struct ResolvedPatch {
+    pub name: String,
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    pub enabled: bool,
+}
+
+/// The reason a manifest entry could not be resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestEntryErrorKind {
+    /// The module is not loaded
+    ModuleNotFound { module: Option<String> },
+    /// Neither `pattern` nor `rva` was specified
+    MissingLocation,
+    /// Both `pattern` and `rva` were specified
+    AmbiguousLocation,
+    /// The pattern is invalid or has no match
+    PatternNotFound { pattern: String },
+    /// The pattern matches more than one location
+    MultipleMatches { pattern: String, count: usize },
+    /// The patch does not fit inside the module
+    OutOfBounds { rva: isize, length: usize },
+    /// `expected` and `replacement` have different lengths
+    LengthMismatch { expected: usize, replacement: usize },
+    /// The bytes in memory do not match `expected`
+    ExpectedBytesMismatch { expected: HexBytes, found: HexBytes },
+}
+
+/// A manifest entry that could not be resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntryError {
+    pub name: String,
+    pub kind: ManifestEntryErrorKind,
+}
+
+impl fmt::Display for ManifestEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "patch `{}`: ", self.name)?;
+        match &self.kind {
+            ManifestEntryErrorKind::ModuleNotFound { module } => match module {
+                Some(module) => write!(f, "module `{}` is not loaded", module),
+                None => write!(f, "main module is not available"),
+            },
+            ManifestEntryErrorKind::MissingLocation => {
+                write!(f, "one of `pattern` or `rva` must be specified")
+            }
+            ManifestEntryErrorKind::AmbiguousLocation => {
+                write!(f, "only one of `pattern` or `rva` may be specified")
+            }
+            ManifestEntryErrorKind::PatternNotFound { pattern } => {
+                write!(f, "pattern `{}` was not found", pattern)
+            }
+            ManifestEntryErrorKind::MultipleMatches { pattern, count } => {
+                write!(f, "pattern `{}` matches {} locations", pattern, count)
+            }
+            ManifestEntryErrorKind::OutOfBounds { rva, length } => {
+                write!(
+                    f,
+                    "{} bytes at rva 0x{:x} are outside of the module",
+                    length, rva
+                )
+            }
+            ManifestEntryErrorKind::LengthMismatch {
+                expected,
+                replacement,
+            } => {
+                write!(
+                    f,
+                    "`expected` is {} bytes but `replacement` is {} bytes",
+                    expected, replacement
+                )
+            }
+            ManifestEntryErrorKind::ExpectedBytesMismatch { expected, found } => {
+                write!(f, "expected `{}` but found `{}`", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ManifestEntryError {}
+
+/// The result of resolving a [`Manifest`]: every entry ends up in exactly one of `patches` or
+/// `errors`.
+#[derive(Debug, Clone, Default)]
+pub struct ManifestResolution {
+    pub patches: Vec<ResolvedPatch>,
+    pub errors: Vec<ManifestEntryError>,
+}
+
+impl Manifest {
+    /// Parses a manifest from TOML.
+    pub fn from_toml(source: &str) -> Result<Manifest> {
+        toml::from_str(source).map_err(|source| Error::ManifestParse { source })
+    }
+
+    /// Resolves every entry against the image returned by `image` for its module (`None` for
+    /// the main executable).
+    pub fn resolve<'a>(
+        &self,
+        mut image: impl FnMut(Option<&str>) -> Option<&'a dyn Image>,
+    ) -> ManifestResolution {
+        let mut resolution = ManifestResolution::default();
+        for entry in &self.entries {
+            match entry.resolve(&mut image) {
+                Ok(patch) => resolution.patches.push(patch),
+                Err(kind) => resolution.errors.push(ManifestEntryError {
+                    name: entry.name.clone(),
+                    kind,
+                }),
+            }
+        }
+        resolution
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Manifest {
+    /// Resolves every entry against the modules loaded in the current process.
+    ///
+    /// Module names are matched against the module filename, ignoring case.
+    pub fn resolve_loaded_modules(&self) -> ManifestResolution {
+        let main = crate::module::Module::find(None).ok();
+        let modules: Vec<_> = crate::module::Module::get_all().collect();
+        self.resolve(|name| {
+            let module = match name {
+                None => main.as_ref(),
+                Some(name) => modules.iter().find(|module| {
+                    module
+                        .filename()
+                        .is_some_and(|filename| filename.eq_ignore_ascii_case(name))
+                }),
+            };
+            module.map(|module| module as &dyn Image)
+        })
+    }
+}
+
+impl ManifestEntry {
+    fn resolve<'a>(
+        &self,
+        image: &mut impl FnMut(Option<&str>) -> Option<&'a dyn Image>,
+    ) -> std::result::Result<ResolvedPatch, ManifestEntryErrorKind> {
+        let replacement = &self.replacement.0;
+        if let Some(expected) = &self.expected {
+            if expected.0.len() != replacement.len() {
+                return Err(ManifestEntryErrorKind::LengthMismatch {
+                    expected: expected.0.len(),
+                    replacement: replacement.len(),
+                });
+            }
+        }
+
+        let image = image(self.module.as_deref()).ok_or_else(|| {
+            ManifestEntryErrorKind::ModuleNotFound {
+                module: self.module.clone(),
+            }
+        })?;
+        let bytes = image.bytes();
+
+        let location = match (&self.pattern, self.rva) {
+            (Some(pattern), None) => {
+                let matches =
+                    patternscan::scan(io::Cursor::new(bytes), pattern).unwrap_or_default();
+                match matches[..] {
+                    [location] => location,
+                    [] => {
+                        return Err(ManifestEntryErrorKind::PatternNotFound {
+                            pattern: pattern.clone(),
+                        })
+                    }
+                    _ => {
+                        return Err(ManifestEntryErrorKind::MultipleMatches {
+                            pattern: pattern.clone(),
+                            count: matches.len(),
+                        })
+                    }
+                }
+            }
+            (None, Some(rva)) => rva,
+            (None, None) => return Err(ManifestEntryErrorKind::MissingLocation),
+            (Some(_), Some(_)) => return Err(ManifestEntryErrorKind::AmbiguousLocation),
+        };
+
+        let rva = location as isize + self.offset;
+        let range = usize::try_from(rva)
+            .ok()
+            .map(|start| start..start + replacement.len())
+            .filter(|range| range.end <= bytes.len())
+            .ok_or(ManifestEntryErrorKind::OutOfBounds {
+                rva,
+                length: replacement.len(),
+            })?;
+
+        if let Some(expected) = &self.expected {
+            let found = &bytes[range.clone()];
+            if found != expected.0.as_slice() {
+                return Err(ManifestEntryErrorKind::ExpectedBytesMismatch {
+                    expected: expected.clone(),
+                    found: HexBytes(found.to_vec()),
+                });
+            }
+        }
+
+        Ok(ResolvedPatch {
+            name: self.name.clone(),
+            address: image.base() + range.start,
+            bytes: replacement.clone(),
+            enabled: self.enabled,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: usize = 0x40_0000;
+
+    /// 0x10 bytes of padding, `movss xmm0, [rip+0x44332211]; comiss xmm0, xmm1`, more
+    /// padding, then `CC CC` twice.
+    fn image_bytes() -> Vec<u8> {
+        let mut bytes = vec![0x90; 0x10];
+        bytes.extend([0xF3, 0x0F, 0x10, 0x05, 0x11, 0x22, 0x33, 0x44, 0x0F, 0x2F, 0xC1]);
+        bytes.resize(0x30, 0x90);
+        bytes.extend([0xCC, 0xCC, 0x90, 0xCC, 0xCC]);
+        bytes.resize(0x40, 0x90);
+        bytes
+    }
+
+    fn resolve(source: &str) -> ManifestResolution {
+        let bytes = image_bytes();
+        let image = BufferImage {
+            base: BASE,
+            bytes: &bytes,
+        };
+        Manifest::from_toml(source)
+            .unwrap()
+            .resolve(|module| module.is_none().then_some(&image as &dyn Image))
+    }
+
+    fn error(name: &str, kind: ManifestEntryErrorKind) -> ManifestEntryError {
+        ManifestEntryError {
+            name: name.to_owned(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn parses_entries() {
+        let manifest = Manifest::from_toml(
+            r#"
+            [[patch]]
+            name = "Uncap framerate"
+            module = "engine.dll"
+            pattern = "F3 0F 10 05 ? ? ? ? 0F 2F C1"
+            offset = 4
+            expected = "00 00 70 42"
+            replacement = "00 00 F0 42"
+
+            [[patch]]
+            name = "Skip intro"
+            rva = 0x1234
+            replacement = "EB"
+            enabled = false
+            "#,
+        )
+        .unwrap();
+
+        let [framerate, intro] = &manifest.entries[..] else {
+            panic!("expected two entries, got {:?}", manifest.entries);
+        };
+        assert_eq!(framerate.name, "Uncap framerate");
+        assert_eq!(framerate.module.as_deref(), Some("engine.dll"));
+        assert_eq!(
+            framerate.pattern.as_deref(),
+            Some("F3 0F 10 05 ? ? ? ? 0F 2F C1")
+        );
+        assert_eq!(framerate.rva, None);
+        assert_eq!(framerate.offset, 4);
+        assert_eq!(framerate.expected, Some(HexBytes(vec![0x00, 0x00, 0x70, 0x42])));
+        assert_eq!(framerate.replacement, HexBytes(vec![0x00, 0x00, 0xF0, 0x42]));
+        assert!(framerate.enabled);
+
+        assert_eq!(intro.module, None);
+        assert_eq!(intro.pattern, None);
+        assert_eq!(intro.rva, Some(0x1234));
+        assert_eq!(intro.offset, 0);
+        assert_eq!(intro.expected, None);
+        assert!(!intro.enabled);
+
+        assert!(Manifest::from_toml("").unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_toml() {
+        let invalid = [
+            // Not TOML
+            "[[patch]\nname = ",
+            // Missing `replacement`
+            "[[patch]]\nname = \"a\"\nrva = 1",
+            // Not a hex byte
+            "[[patch]]\nname = \"a\"\nrva = 1\nreplacement = \"9\"",
+            "[[patch]]\nname = \"a\"\nrva = 1\nreplacement = \"ZZ\"",
+            // Unknown field
+            "[[patch]]\nname = \"a\"\nrva = 1\nreplacement = \"90\"\nbytes = \"90\"",
+            // Wrong type
+            "[[patch]]\nname = \"a\"\nrva = \"1\"\nreplacement = \"90\"",
+        ];
+        for source in invalid {
+            let result = Manifest::from_toml(source);
+            assert!(
+                matches!(result, Err(Error::ManifestParse { .. })),
+                "{source:?} parsed as {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_rva() {
+        let resolution = resolve(
+            r#"
+            [[patch]]
+            name = "rva"
+            rva = 0x30
+            offset = 1
+            expected = "CC 90"
+            replacement = "EB FE"
+            enabled = false
+            "#,
+        );
+        assert_eq!(resolution.errors, []);
+        let [patch] = &resolution.patches[..] else {
+            panic!("expected one patch, got {:?}", resolution.patches);
+        };
+        assert_eq!(patch.name, "rva");
+        assert_eq!(patch.address, BASE + 0x31);
+        assert_eq!(patch.bytes, [0xEB, 0xFE]);
+        assert!(!patch.enabled);
+    }
+
+    #[test]
+    fn resolves_pattern_and_offset() {
+        let resolution = resolve(
+            r#"
+            [[patch]]
+            name = "pattern"
+            pattern = "F3 0F 10 05 ? ? ? ? 0F 2F C1"
+            offset = 4
+            expected = "11 22 33 44"
+            replacement = "00 00 F0 42"
+            "#,
+        );
+        assert_eq!(resolution.errors, []);
+        let [patch] = &resolution.patches[..] else {
+            panic!("expected one patch, got {:?}", resolution.patches);
+        };
+        assert_eq!(patch.address, BASE + 0x14);
+        assert_eq!(patch.bytes, [0x00, 0x00, 0xF0, 0x42]);
+        assert!(patch.enabled);
+    }
+
+    #[test]
+    fn reports_unresolvable_entries() {
+        let resolution = resolve(
+            r#"
+            [[patch]]
+            name = "not found"
+            pattern = "F3 0F 10 05 ? ? ? ? 0F 2E C1"
+            replacement = "90"
+
+            [[patch]]
+            name = "multiple matches"
+            pattern = "CC CC"
+            replacement = "90 90"
+
+            [[patch]]
+            name = "past the end"
+            rva = 0x3F
+            replacement = "90 90"
+
+            [[patch]]
+            name = "before the start"
+            pattern = "F3 0F 10 05"
+            offset = -17
+            replacement = "90"
+
+            [[patch]]
+            name = "wrong bytes"
+            rva = 0x10
+            expected = "90 90"
+            replacement = "90 90"
+
+            [[patch]]
+            name = "other module"
+            module = "other.dll"
+            rva = 0
+            replacement = "90"
+
+            [[patch]]
+            name = "no location"
+            replacement = "90"
+
+            [[patch]]
+            name = "both locations"
+            pattern = "F3"
+            rva = 0
+            replacement = "90"
+
+            [[patch]]
+            name = "length mismatch"
+            rva = 0
+            expected = "90"
+            replacement = "90 90"
+            "#,
+        );
+
+        assert!(resolution.patches.is_empty());
+        assert_eq!(
+            resolution.errors,
+            [
+                error(
+                    "not found",
+                    ManifestEntryErrorKind::PatternNotFound {
+                        pattern: "F3 0F 10 05 ? ? ? ? 0F 2E C1".to_owned()
+                    }
+                ),
+                error(
+                    "multiple matches",
+                    ManifestEntryErrorKind::MultipleMatches {
+                        pattern: "CC CC".to_owned(),
+                        count: 2
+                    }
+                ),
+                error(
+                    "past the end",
+                    ManifestEntryErrorKind::OutOfBounds {
+                        rva: 0x3F,
+                        length: 2
+                    }
+                ),
+                error(
+                    "before the start",
+                    ManifestEntryErrorKind::OutOfBounds { rva: -1, length: 1 }
+                ),
+                error(
+                    "wrong bytes",
+                    ManifestEntryErrorKind::ExpectedBytesMismatch {
+                        expected: HexBytes(vec![0x90, 0x90]),
+                        found: HexBytes(vec![0xF3, 0x0F]),
+                    }
+                ),
+                error(
+                    "other module",
+                    ManifestEntryErrorKind::ModuleNotFound {
+                        module: Some("other.dll".to_owned())
+                    }
+                ),
+                error("no location", ManifestEntryErrorKind::MissingLocation),
+                error("both locations", ManifestEntryErrorKind::AmbiguousLocation),
+                error(
+                    "length mismatch",
+                    ManifestEntryErrorKind::LengthMismatch {
+                        expected: 1,
+                        replacement: 2
+                    }
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,639 @@
+//! Hooks that run a callback in the middle of a function, with access to its registers.
+//!
+//! The hooked location is overwritten with a jump to a stub that saves every register into a
+//! [`Context`], calls the callback, restores the (possibly modified) registers, runs the
+//! relocated original instructions and jumps back.
+//!
+//! Code generation only depends on addresses, so it can be used on any platform; on Windows,
+//! [`Patcher::mid_hook`](crate::Patcher::mid_hook) takes care of allocating and installing it.
+
+use std::{mem, sync::Arc};
+
+use crate::{
+    error::{Error, Result},
+    x86::{self, Mode},
+};
+
+/// The registers at a mid-function hook.
+///
+/// Changes made by the callback are written back before execution resumes, except for `rsp`,
+/// which is read-only.
+#[cfg(target_arch = "x86_64")]
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub xmm: [u128; 16],
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64,
+}
+
+/// The registers at a mid-function hook.
+///
+/// Changes made by the callback are written back before execution resumes, except for `esp`,
+/// which is read-only.
+#[cfg(target_arch = "x86")]
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub xmm: [u128; 8],
+    pub eax: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub ebx: u32,
+    pub esp: u32,
+    pub ebp: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub eflags: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+mod layout {
+    use super::Context;
+
+    pub const XMM: usize = std::mem::offset_of!(Context, xmm);
+    pub const XMM_COUNT: u8 = 16;
+    pub const GPRS: usize = std::mem::offset_of!(Context, rax);
+    pub const GPR_COUNT: u8 = 16;
+    pub const FLAGS: usize = std::mem::offset_of!(Context, rflags);
+    /// The red zone below the stack pointer that leaf functions may use without adjusting it.
+    pub const RED_ZONE: usize = if cfg!(target_os = "windows") { 0 } else { 128 };
+}
+
+#[cfg(target_arch = "x86")]
+mod layout {
+    use super::Context;
+
+    pub const XMM: usize = std::mem::offset_of!(Context, xmm);
+    pub const XMM_COUNT: u8 = 8;
+    pub const GPRS: usize = std::mem::offset_of!(Context, eax);
+    pub const GPR_COUNT: u8 = 8;
+    pub const FLAGS: usize = std::mem::offset_of!(Context, eflags);
+    pub const RED_ZONE: usize = 0;
+}
+
+/// A callback invoked with the registers at a mid-function hook.
+pub type MidHookCallback = dyn Fn(&mut Context) + Send + Sync;
+
+/// The largest stub that [`MidHook::generate`] produces.
+pub const MAX_STUB_LENGTH: usize = 1024;
+
+/// The most bytes at the hooked location that may be needed by [`MidHook::generate`]: the
+/// entry jump may end partway through an instruction, which is then relocated whole.
+pub const MAX_OVERWRITTEN_LENGTH: usize = ENTRY_LENGTH + x86::MAX_INSTRUCTION_LENGTH - 1;
+
+/// The length of the `jmp rel32` written over the hooked location.
+const ENTRY_LENGTH: usize = 5;
+
+const WORD: usize = mem::size_of::<usize>();
+const STACK_POINTER: u8 = 4;
+
+/// The code generated for a [`MidHook`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MidHookCode {
+    /// The stub to place at the stub address.
+    pub stub: Vec<u8>,
+    /// The jump to the stub that replaces the instructions at the hooked location, padded with
+    /// NOPs to the end of the last overwritten instruction.
+    pub entry: Vec<u8>,
+    /// Where execution should continue in the stub for each overwritten instruction, as pairs
+    /// of addresses. A thread about to execute the first instruction continues at the start of
+    /// the stub, so that it still runs the callback.
+    pub instruction_map: Vec<(usize, usize)>,
+}
+
+/// A callback to be run in the middle of a function.
+///
+/// The generated stub refers to the callback by address, so the `MidHook` must outlive any
+/// code generated from it.
+pub struct MidHook {
+    callback: Box<Arc<MidHookCallback>>,
+}
+
+impl MidHook {
+    pub fn new(callback: Arc<MidHookCallback>) -> MidHook {
+        MidHook {
+            callback: Box::new(callback),
+        }
+    }
+
+    /// Generates the code to hook `target`, where `code` holds the original bytes at `target`
+    /// (at least [`MAX_OVERWRITTEN_LENGTH`] of them, if available) and the stub will be
+    /// placed at `stub_address`, which must be within rel32 range of `target`.
+    pub fn generate(&self, target: usize, code: &[u8], stub_address: usize) -> Result<MidHookCode> {
+        let mut stub = self.save_and_call();
+
+        let relocated_address = stub_address + stub.len();
+        let relocation =
+            x86::relocate(code, target, relocated_address, ENTRY_LENGTH, Mode::NATIVE)?;
+        let instruction_map = relocation
+            .offsets
+            .iter()
+            .map(|(source, relocated)| match source {
+                0 => (target, stub_address),
+                _ => (target + source, relocated_address + relocated),
+            })
+            .collect();
+        stub.extend(relocation.bytes);
+        stub.extend(x86::jump(
+            stub_address + stub.len(),
+            target + relocation.source_length,
+            Mode::NATIVE,
+        ));
+        debug_assert!(stub.len() <= MAX_STUB_LENGTH);
+
+        let mut entry = x86::jump(target, stub_address, Mode::NATIVE);
+        if entry.len() != ENTRY_LENGTH {
+            return Err(Error::TargetOutOfRange {
+                address: target,
+                target: stub_address,
+            });
+        }
+        entry.resize(relocation.source_length, 0x90);
+
+        Ok(MidHookCode {
+            stub,
+            entry,
+            instruction_map,
+        })
+    }
+
+    /// Generates a stub to be placed at `stub_address` that runs the callback and then jumps to
+    /// `destination`, with the registers (as modified by the callback) and the stack as they
+    /// were on entry. Calling the stub is equivalent to calling `destination`, whatever its
+    /// calling convention.
+    pub fn generate_thunk(&self, stub_address: usize, destination: usize) -> Vec<u8> {
+        let mut stub = self.save_and_call();
+        stub.extend(x86::jump(
+            stub_address + stub.len(),
+            destination,
+            Mode::NATIVE,
+        ));
+        debug_assert!(stub.len() <= MAX_STUB_LENGTH);
+        stub
+    }
+
+    /// Generates the part of the stub that saves the registers, calls the callback and restores
+    /// the registers, leaving the stack as it was on entry.
+    fn save_and_call(&self) -> Vec<u8> {
+        use layout::*;
+
+        let context_size = mem::size_of::<Context>();
+        let frame_size = (context_size + WORD).next_multiple_of(16);
+        let gprs = (0..GPR_COUNT).filter(|register| *register != STACK_POINTER);
+
+        let mut code = vec![];
+        if RED_ZONE != 0 {
+            // lea rsp, [rsp-128]
+            code.extend([0x48, 0x8D, 0x64, 0x24, 0x80]);
+        }
+        // push rax; pushf; mov rax, rsp; and rsp, -16; sub rsp, frame_size
+        code.extend([0x50, 0x9C]);
+        wide(&mut code, &[0x89, 0xE0]);
+        wide(&mut code, &[0x83, 0xE4, 0xF0]);
+        wide(&mut code, &[0x81, 0xEC]);
+        code.extend((frame_size as u32).to_le_bytes());
+
+        for register in gprs.clone().skip(1) {
+            stack_operand(
+                &mut code,
+                true,
+                &[0x89],
+                register,
+                GPRS + register as usize * WORD,
+            );
+        }
+        // The address of the pushed flags and rax is kept in the frame, just past the context.
+        stack_operand(&mut code, true, &[0x89], 0, context_size);
+        // mov rcx, [rax]; flags
+        wide(&mut code, &[0x8B, 0x08]);
+        stack_operand(&mut code, true, &[0x89], 1, FLAGS);
+        // mov rcx, [rax+WORD]; rax
+        wide(&mut code, &[0x8B, 0x48, WORD as u8]);
+        stack_operand(&mut code, true, &[0x89], 1, GPRS);
+        // lea rcx, [rax+...]; rsp before the hook
+        wide(&mut code, &[0x8D, 0x88]);
+        code.extend(((2 * WORD + RED_ZONE) as u32).to_le_bytes());
+        stack_operand(
+            &mut code,
+            true,
+            &[0x89],
+            1,
+            GPRS + STACK_POINTER as usize * WORD,
+        );
+        for register in 0..XMM_COUNT {
+            // movaps [rsp+...], xmm
+            stack_operand(
+                &mut code,
+                false,
+                &[0x0F, 0x29],
+                register,
+                XMM + register as usize * 16,
+            );
+        }
+
+        // cld; the callback is entitled to a clear direction flag
+        code.push(0xFC);
+        self.call_dispatch(&mut code);
+
+        for register in 0..XMM_COUNT {
+            // movaps xmm, [rsp+...]
+            stack_operand(
+                &mut code,
+                false,
+                &[0x0F, 0x28],
+                register,
+                XMM + register as usize * 16,
+            );
+        }
+        stack_operand(&mut code, true, &[0x8B], 0, context_size);
+        // Write the flags and rax back to where they were pushed.
+        stack_operand(&mut code, true, &[0x8B], 1, FLAGS);
+        // mov [rax], rcx
+        wide(&mut code, &[0x89, 0x08]);
+        stack_operand(&mut code, true, &[0x8B], 1, GPRS);
+        // mov [rax+WORD], rcx
+        wide(&mut code, &[0x89, 0x48, WORD as u8]);
+        for register in gprs.skip(1) {
+            stack_operand(
+                &mut code,
+                true,
+                &[0x8B],
+                register,
+                GPRS + register as usize * WORD,
+            );
+        }
+        // mov rsp, rax; popf; pop rax
+        wide(&mut code, &[0x89, 0xC4]);
+        code.extend([0x9D, 0x58]);
+        if RED_ZONE != 0 {
+            // lea rsp, [rsp+128]
+            code.extend([0x48, 0x8D, 0xA4, 0x24, 0x80, 0x00, 0x00, 0x00]);
+        }
+
+        code
+    }
+
+    /// Emits a call to `dispatch` with the context at the stack pointer, which is 16-byte
+    /// aligned.
+    #[cfg(target_arch = "x86_64")]
+    fn call_dispatch(&self, code: &mut Vec<u8>) {
+        let callback = &*self.callback as *const Arc<MidHookCallback> as usize;
+        let dispatch = dispatch as unsafe extern "C" fn(_, _) as usize;
+        if cfg!(target_os = "windows") {
+            // mov rcx, rsp; mov rdx, callback
+            code.extend([0x48, 0x89, 0xE1, 0x48, 0xBA]);
+        } else {
+            // mov rdi, rsp; mov rsi, callback
+            code.extend([0x48, 0x89, 0xE7, 0x48, 0xBE]);
+        }
+        code.extend(callback.to_le_bytes());
+        // mov rax, dispatch
+        code.extend([0x48, 0xB8]);
+        code.extend(dispatch.to_le_bytes());
+        if cfg!(target_os = "windows") {
+            // sub rsp, 32; call rax; add rsp, 32
+            code.extend([0x48, 0x83, 0xEC, 0x20, 0xFF, 0xD0, 0x48, 0x83, 0xC4, 0x20]);
+        } else {
+            // call rax
+            code.extend([0xFF, 0xD0]);
+        }
+    }
+
+    /// Emits a call to `dispatch` with the context at the stack pointer, which is 16-byte
+    /// aligned.
+    #[cfg(target_arch = "x86")]
+    fn call_dispatch(&self, code: &mut Vec<u8>) {
+        let callback = &*self.callback as *const Arc<MidHookCallback> as usize;
+        let dispatch = dispatch as unsafe extern "C" fn(_, _) as usize;
+        // mov ecx, esp; sub esp, 8; push callback; push ecx
+        code.extend([0x89, 0xE1, 0x83, 0xEC, 0x08, 0x68]);
+        code.extend(callback.to_le_bytes());
+        code.push(0x51);
+        // mov eax, dispatch; call eax; add esp, 16
+        code.push(0xB8);
+        code.extend(dispatch.to_le_bytes());
+        code.extend([0xFF, 0xD0, 0x83, 0xC4, 0x10]);
+    }
+}
+
+unsafe extern "C" fn dispatch(context: *mut Context, callback: *const Arc<MidHookCallback>) {
+    (*callback)(&mut *context)
+}
+
+/// Emits `bytes`, with a REX.W prefix on x64.
+fn wide(code: &mut Vec<u8>, bytes: &[u8]) {
+    if cfg!(target_arch = "x86_64") {
+        code.push(0x48);
+    }
+    code.extend_from_slice(bytes);
+}
+
+/// Emits an instruction with `register` as its register operand and `[rsp+offset]` as its
+/// memory operand. `wide` selects a 64-bit general purpose register on x64.
+fn stack_operand(code: &mut Vec<u8>, wide: bool, opcode: &[u8], register: u8, offset: usize) {
+    let rex = match (wide && cfg!(target_arch = "x86_64"), register >= 8) {
+        (true, extended) => 0x48 | (extended as u8) << 2,
+        (false, true) => 0x44,
+        (false, false) => 0,
+    };
+    if rex != 0 {
+        code.push(rex);
+    }
+    code.extend_from_slice(opcode);
+    code.extend([0x84 | (register & 7) << 3, 0x24]);
+    code.extend((offset as u32).to_le_bytes());
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::{
+        hint::black_box,
+        mem::offset_of,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use super::*;
+    use crate::test_util::ExecutableMemory;
+
+    /// `nop dword ptr [rax+rax]`, which the tests hook.
+    const HOOK_SITE: [u8; 5] = [0x0F, 0x1F, 0x44, 0x00, 0x00];
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Registers {
+        gprs: [u64; 16],
+        flags: u64,
+        xmm: [u128; 16],
+    }
+
+    fn gpr_value(register: u8) -> u64 {
+        0x0101_0101_0101_0101 * (register as u64 + 1)
+    }
+
+    fn xmm_value(register: u8) -> u128 {
+        0x2222_0000_0000_0000_1111_0000_0000_0000 | (register as u128) << 8
+    }
+
+    fn gprs(context: &mut Context) -> [&mut u64; 16] {
+        [
+            &mut context.rax,
+            &mut context.rcx,
+            &mut context.rdx,
+            &mut context.rbx,
+            &mut context.rsp,
+            &mut context.rbp,
+            &mut context.rsi,
+            &mut context.rdi,
+            &mut context.r8,
+            &mut context.r9,
+            &mut context.r10,
+            &mut context.r11,
+            &mut context.r12,
+            &mut context.r13,
+            &mut context.r14,
+            &mut context.r15,
+        ]
+    }
+
+    /// `mov r64, imm64`
+    fn mov_immediate(code: &mut Vec<u8>, register: u8, value: u64) {
+        code.extend([0x48 | (register >> 3), 0xB8 | (register & 7)]);
+        code.extend(value.to_le_bytes());
+    }
+
+    /// `mov [rdi+offset], r64`
+    fn store(code: &mut Vec<u8>, register: u8, offset: usize) {
+        code.extend([0x48 | (register >> 3) << 2, 0x89, 0x87 | (register & 7) << 3]);
+        code.extend((offset as u32).to_le_bytes());
+    }
+
+    /// Hooks the `HOOK_SITE` at `offset` in `memory`, placing the stub at `stub_offset`.
+    fn install(memory: &ExecutableMemory, offset: usize, hook: &MidHook, stub_offset: usize) {
+        let target = memory.address() + offset;
+        let code = &memory.bytes(offset)[..MAX_OVERWRITTEN_LENGTH];
+        let generated = hook
+            .generate(target, code, memory.address() + stub_offset)
+            .unwrap();
+        assert!(generated.stub.len() <= MAX_STUB_LENGTH);
+        assert_eq!(generated.entry.len(), HOOK_SITE.len());
+        assert_eq!(generated.instruction_map, [(target, memory.address() + stub_offset)]);
+        memory.write(stub_offset, &generated.stub);
+        memory.write(offset, &generated.entry);
+    }
+
+    /// Records the context seen by the callback, after applying `modify` to it.
+    fn recording_hook(
+        modify: impl Fn(&mut Context) + Send + Sync + 'static,
+    ) -> (MidHook, Arc<Mutex<Option<Context>>>) {
+        let seen = Arc::new(Mutex::new(None));
+        let recorded = seen.clone();
+        let hook = MidHook::new(Arc::new(move |context: &mut Context| {
+            *recorded.lock().unwrap() = Some(*context);
+            modify(context);
+        }));
+        (hook, seen)
+    }
+
+    #[test]
+    fn registers_round_trip() {
+        let mut code = vec![];
+        // push rbx; push rbp; push r12; push r13; push r14; push r15; push rdi
+        code.extend([0x53, 0x55, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57, 0x57]);
+        for register in 0..16 {
+            let value = xmm_value(register);
+            let rex = 0x48 | (register >> 3) << 2;
+            let modrm = 0xC0 | (register & 7) << 3;
+            // movq xmm, rax
+            mov_immediate(&mut code, 0, value as u64);
+            code.extend([0x66, rex, 0x0F, 0x6E, modrm]);
+            // pinsrq xmm, rax, 1
+            mov_immediate(&mut code, 0, (value >> 64) as u64);
+            code.extend([0x66, rex, 0x0F, 0x3A, 0x22, modrm, 0x01]);
+        }
+        for register in (0..16).filter(|register| *register != STACK_POINTER) {
+            mov_immediate(&mut code, register, gpr_value(register));
+        }
+        // stc
+        code.push(0xF9);
+        let hook_offset = code.len();
+        code.extend(HOOK_SITE);
+        // pushf; xchg rdi, [rsp+8]
+        code.extend([0x9C, 0x48, 0x87, 0x7C, 0x24, 0x08]);
+        for register in (0..16).filter(|register| ![STACK_POINTER, 7].contains(register)) {
+            store(&mut code, register, register as usize * 8);
+        }
+        for register in 0..16u8 {
+            // movdqu [rdi+offset], xmm
+            code.push(0xF3);
+            if register >= 8 {
+                code.push(0x44);
+            }
+            code.extend([0x0F, 0x7F, 0x87 | (register & 7) << 3]);
+            let offset = offset_of!(Registers, xmm) + register as usize * 16;
+            code.extend((offset as u32).to_le_bytes());
+        }
+        // pop rax (the flags), then pop rax (rdi)
+        code.push(0x58);
+        store(&mut code, 0, offset_of!(Registers, flags));
+        code.push(0x58);
+        store(&mut code, 0, 7 * 8);
+        // pop r15; pop r14; pop r13; pop r12; pop rbp; pop rbx; ret
+        code.extend([0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5D, 0x5B, 0xC3]);
+        code.resize(code.len() + MAX_OVERWRITTEN_LENGTH, 0xCC);
+
+        let memory = ExecutableMemory::new(0x2000);
+        memory.write(0, &code);
+        let (hook, seen) = recording_hook(|context| {
+            for (register, value) in gprs(context).into_iter().enumerate() {
+                if register != STACK_POINTER as usize {
+                    *value = !*value;
+                }
+            }
+            for value in &mut context.xmm {
+                *value = value.rotate_left(64);
+            }
+            // Clear CF, set ZF.
+            context.rflags = context.rflags & !0x01 | 0x40;
+        });
+        install(&memory, hook_offset, &hook, 0x1000);
+
+        let mut registers = Registers::default();
+        unsafe {
+            let function: unsafe extern "sysv64" fn(*mut Registers) = memory.function(0);
+            function(&mut registers);
+        }
+
+        let mut seen = seen.lock().unwrap().expect("callback was not called");
+        assert_eq!(seen.rsp % 16, 0);
+        for (register, value) in gprs(&mut seen).into_iter().enumerate() {
+            if register != STACK_POINTER as usize {
+                assert_eq!(*value, gpr_value(register as u8), "register {register}");
+            }
+        }
+        assert_eq!(seen.rflags & 0x01, 0x01);
+        for (register, value) in seen.xmm.iter().enumerate() {
+            assert_eq!(*value, xmm_value(register as u8), "xmm{register}");
+        }
+
+        for (register, value) in registers.gprs.iter().enumerate() {
+            if register != STACK_POINTER as usize {
+                assert_eq!(*value, !gpr_value(register as u8), "register {register}");
+            }
+        }
+        assert_eq!(registers.flags & 0x41, 0x40);
+        for (register, value) in registers.xmm.iter().enumerate() {
+            let expected = xmm_value(register as u8).rotate_left(64);
+            assert_eq!(*value, expected, "xmm{register}");
+        }
+    }
+
+    #[test]
+    fn stack_is_aligned_for_callback() {
+        #[repr(align(16))]
+        struct Aligned(#[allow(dead_code)] [u8; 16]);
+
+        // Checks the alignment as seen by the callback, which movaps would fault on as well.
+        let misaligned = Arc::new(AtomicUsize::new(0));
+        let check = |misaligned: Arc<AtomicUsize>| {
+            move |context: &mut Context| {
+                let local = Aligned([0; 16]);
+                let local = black_box(&local) as *const Aligned as usize;
+                let context = black_box(context as *const Context as usize);
+                misaligned.fetch_add(local % 16 + context % 16, Ordering::SeqCst);
+            }
+        };
+        let (entry, seen_entry) = recording_hook(check(misaligned.clone()));
+        let (after_push, seen_after_push) = recording_hook(check(misaligned.clone()));
+
+        let mut code = vec![];
+        code.extend(HOOK_SITE);
+        // push rbx
+        code.push(0x53);
+        let after_push_offset = code.len();
+        code.extend(HOOK_SITE);
+        // pop rbx; ret
+        code.extend([0x5B, 0xC3]);
+        code.resize(code.len() + MAX_OVERWRITTEN_LENGTH, 0xCC);
+
+        let memory = ExecutableMemory::new(0x3000);
+        memory.write(0, &code);
+        install(&memory, 0, &entry, 0x1000);
+        install(&memory, after_push_offset, &after_push, 0x2000);
+
+        unsafe {
+            let function: unsafe extern "sysv64" fn() = memory.function(0);
+            function();
+        }
+        assert_eq!(misaligned.load(Ordering::SeqCst), 0);
+
+        let entry = seen_entry.lock().unwrap().unwrap();
+        let after_push = seen_after_push.lock().unwrap().unwrap();
+        assert_eq!(entry.rsp % 16, 8);
+        assert_eq!(after_push.rsp, entry.rsp - 8);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn red_zone_is_preserved() {
+        fn value(slot: u8) -> u64 {
+            0x0123_4567_89AB_CDEF_u64.rotate_left(slot as u32 * 4)
+        }
+
+        let mut code = vec![];
+        for slot in 1..=16u8 {
+            // mov rax, value; mov [rsp-8*slot], rax
+            mov_immediate(&mut code, 0, value(slot));
+            code.extend([0x48, 0x89, 0x44, 0x24, (slot * 8).wrapping_neg()]);
+        }
+        // mov rcx, rsp
+        code.extend([0x48, 0x89, 0xE1]);
+        let hook_offset = code.len();
+        code.extend(HOOK_SITE);
+        // xor eax, eax
+        code.extend([0x31, 0xC0]);
+        for slot in 1..=16u8 {
+            // add rax, [rsp-8*slot]
+            code.extend([0x48, 0x03, 0x44, 0x24, (slot * 8).wrapping_neg()]);
+        }
+        code.push(0xC3);
+        code.resize(code.len() + MAX_OVERWRITTEN_LENGTH, 0xCC);
+
+        let memory = ExecutableMemory::new(0x2000);
+        memory.write(0, &code);
+        // The callback uses plenty of stack of its own.
+        let (hook, seen) = recording_hook(|context| {
+            black_box([0xAA_u8; 512]);
+            context.rdx = 0;
+        });
+        install(&memory, hook_offset, &hook, 0x1000);
+
+        let result = unsafe {
+            let function: unsafe extern "sysv64" fn() -> u64 = memory.function(0);
+            function()
+        };
+        let expected = (1..=16).map(value).fold(0, u64::wrapping_add);
+        assert_eq!(result, expected);
+
+        let seen = seen.lock().unwrap().unwrap();
+        assert_eq!(seen.rsp, seen.rcx);
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Storage for the single instance of a type that is created and destroyed at runtime, such
+/// as a payload's state. Used by `#[derive(Singleton)]`.
+///
+/// The instance is shared as an [`Arc`], so a detour running on a game thread can keep using
+/// it while it is being replaced or destroyed.
+pub struct Singleton<T> {
+    instance: RwLock<Option<Arc<T>>>,
+}
+
+impl<T> Singleton<T> {
+    pub const fn new() -> Self {
+        Singleton {
+            instance: RwLock::new(None),
+        }
+    }
+
+    /// Sets the instance, returning the previous one.
+    pub fn set(&self, instance: T) -> Option<Arc<T>> {
+        self.instance.write().unwrap().replace(Arc::new(instance))
+    }
+
+    /// Returns the instance, if it is set.
+    ///
+    /// The returned `Arc` keeps the instance alive, and [`Singleton::destroy`] waits for it to
+    /// be dropped: drop it as soon as possible, and never hold it on the thread that calls
+    /// `destroy` (or across anything that waits on that thread), as `destroy` would then time
+    /// out.
+    pub fn get(&self) -> Option<Arc<T>> {
+        self.instance.read().unwrap().clone()
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.instance.read().unwrap().is_some()
+    }
+
+    /// Removes the instance, without waiting for other references to it to be dropped.
+    pub fn take(&self) -> Option<Arc<T>> {
+        self.instance.write().unwrap().take()
+    }
+
+    /// Removes the instance, then waits up to `timeout` for every other reference to it to be
+    /// dropped before dropping it on this thread. This makes it safe to unload the payload once
+    /// this returns `Ok`, as no other thread can still be running the instance's code.
+    ///
+    /// If other references remain after `timeout`, the instance is returned instead, and the
+    /// payload must not be unloaded while it is alive.
+    pub fn destroy(&self, timeout: Duration) -> Result<(), Arc<T>> {
+        let Some(mut instance) = self.take() else {
+            return Ok(());
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            match Arc::try_unwrap(instance) {
+                Ok(instance) => {
+                    drop(instance);
+                    return Ok(());
+                }
+                Err(shared) if Instant::now() >= deadline => return Err(shared),
+                Err(shared) => {
+                    instance = shared;
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+    }
+}
+
+impl<T> Default for Singleton<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destroy_waits_for_other_references() {
+        let singleton = Arc::new(Singleton::new());
+        singleton.set(1);
+        let instance = singleton.get().unwrap();
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(instance);
+        });
+
+        let start = Instant::now();
+        assert_eq!(singleton.destroy(Duration::from_secs(10)), Ok(()));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(!singleton.is_set());
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn destroy_times_out_while_in_use() {
+        let singleton = Singleton::new();
+        singleton.set(1);
+        let instance = singleton.get().unwrap();
+
+        let remaining = singleton.destroy(Duration::from_millis(10)).unwrap_err();
+        assert!(Arc::ptr_eq(&instance, &remaining));
+        assert!(!singleton.is_set());
+        assert_eq!(singleton.destroy(Duration::ZERO), Ok(()));
+    }
+}
@@ -0,0 +1,104 @@
+//! Helpers for tests that generate code and run it.
+
+use crate::function::Function;
+
+/// A page-aligned region of readable, writable and executable memory.
+pub struct ExecutableMemory {
+    base: *mut u8,
+    length: usize,
+}
+
+impl ExecutableMemory {
+    pub fn new(length: usize) -> ExecutableMemory {
+        let base = unsafe { sys::allocate(length) };
+        assert!(!base.is_null(), "failed to allocate executable memory");
+        ExecutableMemory { base, length }
+    }
+
+    pub fn address(&self) -> usize {
+        self.base as usize
+    }
+
+    /// Copies `bytes` to `offset`, returning the address they were written to.
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> usize {
+        assert!(offset + bytes.len() <= self.length);
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.base.add(offset), bytes.len()) };
+        self.address() + offset
+    }
+
+    /// The bytes from `offset` to the end of the region.
+    pub fn bytes(&self, offset: usize) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.base.add(offset), self.length - offset) }
+    }
+
+    /// # Safety
+    /// The code at `offset` must be a function of type `F`.
+    pub unsafe fn function<F: Function>(&self, offset: usize) -> F {
+        F::from_ptr(self.base.add(offset) as *const ())
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        unsafe { sys::free(self.base, self.length) }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::c_void;
+
+    const PROT_READ_WRITE_EXEC: i32 = 0x1 | 0x2 | 0x4;
+    #[cfg(target_os = "linux")]
+    const MAP_PRIVATE_ANONYMOUS: i32 = 0x02 | 0x20;
+    #[cfg(not(target_os = "linux"))]
+    const MAP_PRIVATE_ANONYMOUS: i32 = 0x0002 | 0x1000;
+    const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        fn mmap(
+            address: *mut c_void,
+            length: usize,
+            protection: i32,
+            flags: i32,
+            fd: i32,
+            offset: i64,
+        ) -> *mut c_void;
+        fn munmap(address: *mut c_void, length: usize) -> i32;
+    }
+
+    pub unsafe fn allocate(length: usize) -> *mut u8 {
+        let base = mmap(
+            std::ptr::null_mut(),
+            length,
+            PROT_READ_WRITE_EXEC,
+            MAP_PRIVATE_ANONYMOUS,
+            -1,
+            0,
+        );
+        if base == MAP_FAILED {
+            std::ptr::null_mut()
+        } else {
+            base as *mut u8
+        }
+    }
+
+    pub unsafe fn free(base: *mut u8, length: usize) {
+        munmap(base as *mut c_void, length);
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use windows::Win32::System::Memory::{
+        VirtualAlloc, VirtualFree, MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE_READWRITE,
+    };
+
+    pub unsafe fn allocate(length: usize) -> *mut u8 {
+        VirtualAlloc(None, length, MEM_COMMIT | MEM_RESERVE, PAGE_EXECUTE_READWRITE) as *mut u8
+    }
+
+    pub unsafe fn free(base: *mut u8, _length: usize) {
+        let _ = VirtualFree(base as _, 0, MEM_RELEASE);
+    }
+}
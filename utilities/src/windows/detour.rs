@@ -0,0 +1,448 @@
+use std::{fmt, sync::Mutex};
+
+use super::{
+    detour_binder::DetourBinder,
+    hook_chain::ChainedHook,
+    near_allocator::NearAllocation,
+    patcher::{self, Patcher},
+    thread_suspender,
+};
+use crate::{
+    closure::{self, ClosureFunction, ClosureThunk},
+    error::{Result, UserCallbackResult},
+    function::Function,
+    hook_engine::HookEngine,
+    inline_hook,
+};
+
+/// The engine used by [`Detour`] and `#[detour]` when none is given.
+pub type DefaultEngine = InlineEngine;
+
+/// The crate's own [`HookEngine`], which installs the code generated by
+/// [`inline_hook::generate`].
+///
+/// The trampoline is allocated within rel32 range of the target, so that relocated
+/// RIP-relative operands can still reach their targets. The target is written through the
+/// engine's own [`Patcher`], and threads suspended by a
+/// [`ThreadSuspender`](super::ThreadSuspender) that are about to execute an overwritten
+/// instruction are moved to its copy in the trampoline.
+pub struct InlineEngine {
+    target: usize,
+    trampoline: usize,
+    entry: Vec<u8>,
+    instruction_map: Vec<(usize, usize)>,
+    // Declared before `_trampoline_memory`, so that the target is restored before the
+    // trampoline is freed.
+    patcher: Mutex<Patcher>,
+    _trampoline_memory: NearAllocation,
+}
+
+impl HookEngine for InlineEngine {
+    unsafe fn new(target: *const (), detour: *const ()) -> Result<Self> {
+        let target = target as usize;
+        let memory = NearAllocation::new(target, inline_hook::MAX_TRAMPOLINE_LENGTH)?;
+        let trampoline = memory.as_ptr();
+        let code = patcher::readable_code(target, inline_hook::MAX_OVERWRITTEN_LENGTH);
+        let generated = inline_hook::generate(target, code, trampoline as usize, detour as usize)?;
+        std::ptr::copy_nonoverlapping(
+            generated.trampoline.as_ptr(),
+            trampoline,
+            generated.trampoline.len(),
+        );
+
+        Ok(InlineEngine {
+            target,
+            trampoline: trampoline as usize,
+            entry: generated.entry,
+            instruction_map: generated.instruction_map,
+            patcher: Mutex::new(Patcher::new()),
+            _trampoline_memory: memory,
+        })
+    }
+
+    unsafe fn enable(&self) -> Result<()> {
+        let mut patcher = self.patcher.lock().unwrap();
+        if patcher.original_bytes(self.target).is_some() {
+            return Ok(());
+        }
+        thread_suspender::relocate_suspended_instruction_pointers(
+            self.target..self.target + self.entry.len(),
+            |ip| {
+                self.instruction_map
+                    .iter()
+                    .find(|(original, _)| *original == ip)
+                    .map(|(_, relocated)| *relocated)
+            },
+        )?;
+        patcher.patch(self.target, &self.entry)
+    }
+
+    unsafe fn disable(&self) -> Result<()> {
+        self.patcher.lock().unwrap().unpatch(self.target);
+        Ok(())
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.patcher
+            .lock()
+            .unwrap()
+            .original_bytes(self.target)
+            .is_some()
+    }
+
+    fn trampoline(&self) -> *const () {
+        self.trampoline as *const ()
+    }
+
+    fn target(&self) -> *const () {
+        self.target as *const ()
+    }
+}
+
+/// A [`HookEngine`] backed by `retour`.
+///
+/// Like [`InlineEngine`], threads suspended by a [`ThreadSuspender`](super::ThreadSuspender)
+/// that are about to execute an overwritten instruction are moved to its copy in the
+/// trampoline. `retour` does not expose where each instruction ends up, so only the copies it
+/// made verbatim are used; a thread about to execute any other overwritten instruction is
+/// briefly resumed until it has left them.
+#[cfg(feature = "retour")]
+pub struct RetourEngine {
+    target: usize,
+    detour: retour::RawDetour,
+    /// The instructions overwritten by the jump to the detour.
+    overwritten: std::ops::Range<usize>,
+    instruction_map: Vec<(usize, usize)>,
+}
+
+#[cfg(feature = "retour")]
+impl RetourEngine {
+    /// The length of the jump that `retour` writes over the start of the target.
+    const JUMP_LENGTH: usize = 5;
+
+    /// Decodes the instructions at `target` that the jump overwrites, pairing each one that
+    /// is copied verbatim to the same offset in `trampoline` with its copy.
+    unsafe fn map_instructions(
+        target: usize,
+        trampoline: usize,
+    ) -> (std::ops::Range<usize>, Vec<(usize, usize)>) {
+        use crate::x86::{self, Mode};
+
+        let mut instruction_map = vec![];
+        let mut offset = 0;
+        let mut verbatim = true;
+        while offset < Self::JUMP_LENGTH {
+            let code = patcher::readable_code(target + offset, x86::MAX_INSTRUCTION_LENGTH);
+            let Some(instruction) = x86::decode(code, Mode::NATIVE) else {
+                break;
+            };
+            let copy =
+                std::slice::from_raw_parts((trampoline + offset) as *const u8, instruction.length);
+            // Anything after a rewritten instruction may have moved.
+            verbatim = verbatim && copy == &code[..instruction.length];
+            if verbatim {
+                instruction_map.push((target + offset, trampoline + offset));
+            }
+            offset += instruction.length;
+        }
+        (
+            target..target + offset.max(Self::JUMP_LENGTH),
+            instruction_map,
+        )
+    }
+
+    fn relocate_suspended_threads(&self) -> Result<()> {
+        thread_suspender::relocate_suspended_instruction_pointers(self.overwritten.clone(), |ip| {
+            self.instruction_map
+                .iter()
+                .find(|(original, _)| *original == ip)
+                .map(|(_, relocated)| *relocated)
+        })
+    }
+}
+
+#[cfg(feature = "retour")]
+impl HookEngine for RetourEngine {
+    unsafe fn new(target: *const (), detour: *const ()) -> Result<Self> {
+        let detour = retour::RawDetour::new(target, detour)?;
+        let target = target as usize;
+        let trampoline = detour.trampoline() as *const () as usize;
+        let (overwritten, instruction_map) = Self::map_instructions(target, trampoline);
+        Ok(RetourEngine {
+            target,
+            detour,
+            overwritten,
+            instruction_map,
+        })
+    }
+
+    unsafe fn enable(&self) -> Result<()> {
+        if !self.detour.is_enabled() {
+            self.relocate_suspended_threads()?;
+        }
+        Ok(self.detour.enable()?)
+    }
+
+    unsafe fn disable(&self) -> Result<()> {
+        Ok(self.detour.disable()?)
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.detour.is_enabled()
+    }
+
+    fn trampoline(&self) -> *const () {
+        self.detour.trampoline() as *const ()
+    }
+
+    fn target(&self) -> *const () {
+        self.target as *const ()
+    }
+}
+
+/// A hook that redirects a function of type `F` to a detour of the same type, installed by
+/// the engine `E`. Generated by `#[detour]`.
+///
+/// Detours are attached to the function's [`ChainedHook`] chain, so any number of them can
+/// hook the same function: [`Detour::original`] calls the next enabled detour with a lower
+/// priority, or the original function.
+pub struct Detour<F: Function, E: HookEngine = DefaultEngine> {
+    hook: ChainedHook<F, E>,
+}
+
+impl<F: Function, E: HookEngine> Detour<F, E> {
+    /// Prepares a hook that redirects `target` to `detour`, with a priority of 0. The hook
+    /// starts disabled.
+    ///
+    /// # Safety
+    /// `target` must be long enough to be overwritten by a jump.
+    pub unsafe fn new(target: F, detour: F) -> Result<Self> {
+        Self::with_priority(target, detour, 0)
+    }
+
+    /// Prepares a hook that redirects `target` to `detour`. Detours on the same function are
+    /// called in order of descending priority. The hook starts disabled.
+    ///
+    /// # Safety
+    /// `target` must be long enough to be overwritten by a jump.
+    pub unsafe fn with_priority(target: F, detour: F, priority: i32) -> Result<Self> {
+        Ok(Detour {
+            hook: ChainedHook::new(target, detour, priority)?,
+        })
+    }
+
+    /// # Safety
+    /// No thread may be executing the start of the target while it is being overwritten.
+    pub unsafe fn enable(&self) -> Result<()> {
+        self.hook.enable()
+    }
+
+    /// # Safety
+    /// No thread may be executing the start of the target while it is being restored.
+    pub unsafe fn disable(&self) -> Result<()> {
+        self.hook.disable()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.hook.is_enabled()
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.hook.priority()
+    }
+
+    /// Returns a function that continues down the chain: the next enabled detour on the
+    /// target, or the target as it was before it was hooked.
+    pub fn original(&self) -> F {
+        self.hook.next()
+    }
+
+    pub fn target(&self) -> F {
+        self.hook.target()
+    }
+}
+
+impl<F: Function, E: HookEngine> fmt::Debug for Detour<F, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Detour")
+            .field("target", &self.target().to_ptr())
+            .field("priority", &self.priority())
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+impl<F: Function, E: HookEngine> DetourBinder for Detour<F, E> {
+    fn enable(&self) -> UserCallbackResult<()> {
+        unsafe { Detour::enable(self)? };
+        Ok(())
+    }
+    fn disable(&self) -> UserCallbackResult<()> {
+        unsafe { Detour::disable(self)? };
+        Ok(())
+    }
+    fn address(&self) -> Option<usize> {
+        Some(self.target().to_ptr() as usize)
+    }
+}
+
+/// A detour whose replacement is a boxed closure, so that it can capture state and be created
+/// at runtime (e.g. from a configuration file). The closure is passed the original function,
+/// followed by the arguments; see [`ClosureFunction`].
+///
+/// Calls reach the closure through a thunk that saves and restores every register, which
+/// makes them slower than calls to the function of a [`Detour`].
+pub struct ClosureDetour<F: ClosureFunction, E: HookEngine = DefaultEngine> {
+    // Declared first, so that the target is restored before the thunk is freed.
+    detour: Detour<F, E>,
+    _thunk: ClosureThunk<F>,
+    _thunk_memory: NearAllocation,
+}
+
+impl<F: ClosureFunction, E: HookEngine> ClosureDetour<F, E> {
+    /// Prepares a hook that redirects `target` to `closure`. The hook starts disabled.
+    ///
+    /// # Safety
+    /// `target` must be long enough to be overwritten by a jump.
+    pub unsafe fn new(target: F, closure: Box<F::Closure>) -> Result<Self> {
+        let thunk = ClosureThunk::new(closure, target);
+        let memory = NearAllocation::new(target.to_ptr() as usize, closure::MAX_THUNK_LENGTH)?;
+        let address = memory.as_ptr();
+        let code = thunk.generate(address as usize);
+        std::ptr::copy_nonoverlapping(code.as_ptr(), address, code.len());
+
+        let detour = Detour {
+            hook: ChainedHook::with_next_slot(
+                target,
+                F::from_ptr(address as *const ()),
+                0,
+                thunk.original_slot(),
+            )?,
+        };
+        Ok(ClosureDetour {
+            detour,
+            _thunk: thunk,
+            _thunk_memory: memory,
+        })
+    }
+
+    /// # Safety
+    /// No thread may be executing the start of the target while it is being overwritten.
+    pub unsafe fn enable(&self) -> Result<()> {
+        self.detour.enable()
+    }
+
+    /// # Safety
+    /// No thread may be executing the start of the target while it is being restored.
+    pub unsafe fn disable(&self) -> Result<()> {
+        self.detour.disable()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.detour.is_enabled()
+    }
+
+    /// Returns a function that calls the target as it was before it was hooked.
+    pub fn original(&self) -> F {
+        self.detour.original()
+    }
+
+    pub fn target(&self) -> F {
+        self.detour.target()
+    }
+}
+
+impl<F: ClosureFunction, E: HookEngine> fmt::Debug for ClosureDetour<F, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClosureDetour")
+            .field("detour", &self.detour)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F: ClosureFunction, E: HookEngine> DetourBinder for ClosureDetour<F, E> {
+    fn enable(&self) -> UserCallbackResult<()> {
+        unsafe { ClosureDetour::enable(self)? };
+        Ok(())
+    }
+    fn disable(&self) -> UserCallbackResult<()> {
+        unsafe { ClosureDetour::disable(self)? };
+        Ok(())
+    }
+    fn address(&self) -> Option<usize> {
+        DetourBinder::address(&self.detour)
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{hook_library::HookLibrary, test_util::ExecutableMemory};
+
+    type Target = extern "sysv64" fn(u64) -> u64;
+
+    thread_local! {
+        /// The detour that `detour` belongs to, which is owned by a library.
+        static DETOUR: Cell<*const Detour<Target>> = const { Cell::new(std::ptr::null()) };
+    }
+
+    extern "sysv64" fn detour(x: u64) -> u64 {
+        let detour = unsafe { &*DETOUR.with(Cell::get) };
+        detour.original()(x) * 10 + 2
+    }
+
+    #[test]
+    fn libraries_share_detoured_functions() {
+        let memory = ExecutableMemory::new(4096);
+        // mov rax, rdi; add rax, 1; ret
+        memory.write(0, &[0x48, 0x89, 0xF8, 0x48, 0x83, 0xC0, 0x01, 0xC3]);
+        let target: Target = unsafe { memory.function(0) };
+
+        let first = Box::new(unsafe { Detour::with_priority(target, detour, 1) }.unwrap());
+        DETOUR.with(|cell| cell.set(&*first));
+        let second = unsafe {
+            ClosureDetour::<Target>::new(target, Box::new(|original, x| original(x) * 10 + 5))
+        }
+        .unwrap();
+
+        let a = HookLibrary::new().with_runtime_binder(first);
+        let b = HookLibrary::new().with_closure_detour(second);
+        a.set_enabled(true).unwrap();
+        b.set_enabled(true).unwrap();
+        assert_eq!(target(0), 152);
+
+        a.set_enabled(false).unwrap();
+        assert_eq!(target(0), 15);
+        a.set_enabled(true).unwrap();
+        drop(b);
+        assert_eq!(target(0), 12);
+        drop(a);
+        assert_eq!(target(0), 1);
+    }
+
+    #[test]
+    fn code_is_not_read_past_the_end_of_readable_memory() {
+        use windows::Win32::System::Memory::{
+            VirtualProtect, PAGE_EXECUTE_READWRITE, PAGE_NOACCESS, PAGE_PROTECTION_FLAGS,
+        };
+
+        let memory = ExecutableMemory::new(0x2000);
+        let guard = memory.address() + 0x1000;
+        let mut protection = PAGE_PROTECTION_FLAGS::default();
+        unsafe { VirtualProtect(guard as _, 0x1000, PAGE_NOACCESS, &mut protection) }.unwrap();
+
+        // nop, followed by the inaccessible page
+        let target = memory.write(0xFFF, &[0x90]);
+        assert_eq!(unsafe { patcher::readable_code(target, 16) }, [0x90]);
+        let engine = unsafe { InlineEngine::new(target as *const (), detour as *const ()) };
+        assert!(matches!(
+            engine,
+            Err(crate::error::Error::InstructionDecodeFailed { .. })
+        ));
+
+        unsafe { VirtualProtect(guard as _, 0x1000, PAGE_EXECUTE_READWRITE, &mut protection) }
+            .unwrap();
+    }
+}
@@ -1,11 +1,27 @@
+use std::sync::OnceLock;
+
 use crate::error::UserCallbackResult;
 
 pub trait DetourBinder {
     fn enable(&self) -> UserCallbackResult<()>;
     fn disable(&self) -> UserCallbackResult<()>;
+    /// The name of the hook, used to address it within a
+    /// [`HookLibrary`](super::hook_library::HookLibrary).
+    fn name(&self) -> Option<&str> {
+        None
+    }
+    /// The address of the hooked function, if it has been resolved.
+    fn address(&self) -> Option<usize> {
+        None
+    }
 }
 
 pub struct CompiletimeDetourBinder {
+    pub name: &'static str,
+    /// The group the detour was tagged with, if any.
+    pub group: Option<&'static str>,
+    /// Set when the detour is first enabled.
+    pub address: OnceLock<usize>,
     pub enable: &'static (dyn Send + Sync + Fn() -> UserCallbackResult<()>),
     pub disable: &'static (dyn Send + Sync + Fn() -> UserCallbackResult<()>),
 }
@@ -16,6 +32,12 @@ impl DetourBinder for CompiletimeDetourBinder {
     fn disable(&self) -> UserCallbackResult<()> {
         (self.disable)()
     }
+    fn name(&self) -> Option<&str> {
+        Some(self.name)
+    }
+    fn address(&self) -> Option<usize> {
+        self.address.get().copied()
+    }
 }
 
 pub struct RuntimeDetourBinder {
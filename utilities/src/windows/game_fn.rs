@@ -0,0 +1,87 @@
+use std::{marker::PhantomData, mem, sync::OnceLock};
+
+use crate::error::Result;
+
+/// An address in the game that is found on first use.
+pub(crate) struct LazyAddress {
+    name: &'static str,
+    find: fn() -> Result<usize>,
+    address: OnceLock<usize>,
+}
+
+impl LazyAddress {
+    pub(crate) const fn new(name: &'static str, find: fn() -> Result<usize>) -> Self {
+        LazyAddress {
+            name,
+            find,
+            address: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub(crate) fn resolve(&self) -> Result<usize> {
+        if let Some(address) = self.address.get() {
+            return Ok(*address);
+        }
+        let address = (self.find)()?;
+        Ok(*self.address.get_or_init(|| address))
+    }
+
+    pub(crate) fn get(&self) -> Option<usize> {
+        self.address.get().copied()
+    }
+
+    /// Resolves the address, panicking with a description of the error if it cannot be found.
+    pub(crate) fn expect(&self) -> usize {
+        self.resolve().unwrap_or_else(|e| {
+            panic!("`{}` was used, but could not be resolved: {}", self.name, e)
+        })
+    }
+}
+
+/// A function in the game that is called without being detoured, resolved on first use.
+/// Generated by `#[game_fn]`.
+pub struct GameFn<F> {
+    address: LazyAddress,
+    _function: PhantomData<F>,
+}
+
+impl<F: Copy> GameFn<F> {
+    /// Creates a function named `name` that is found by calling `find`.
+    pub const fn new(name: &'static str, find: fn() -> Result<usize>) -> Self {
+        assert!(mem::size_of::<F>() == mem::size_of::<usize>());
+        GameFn {
+            address: LazyAddress::new(name, find),
+            _function: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.address.name()
+    }
+
+    /// Finds the function if it hasn't been found yet, returning its address.
+    pub fn resolve(&self) -> Result<usize> {
+        self.address.resolve()
+    }
+
+    /// The address of the function, if it has been resolved.
+    pub fn address(&self) -> Option<usize> {
+        self.address.get()
+    }
+
+    /// Returns the function, resolving it if necessary.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the function cannot be found. Call [`GameFn::resolve`] beforehand to handle
+    /// the error instead.
+    pub fn get(&self) -> F {
+        let address = self.address.expect();
+        // SAFETY: `F` is the function's pointer type, which is the size of an address.
+        unsafe { mem::transmute_copy(&address) }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{marker::PhantomData, ptr};
+
+use super::game_fn::LazyAddress;
+use crate::error::Result;
+
+/// A global variable in the game, resolved on first use. Generated by `#[game_static]`.
+///
+/// If the global is reached through pointers (`deref` levels), they are followed on every
+/// access, as the game may change them.
+pub struct GameStatic<T> {
+    address: LazyAddress,
+    deref: usize,
+    _value: PhantomData<*mut T>,
+}
+
+// SAFETY: only the address is shared; accessing the value is unsafe.
+unsafe impl<T> Sync for GameStatic<T> {}
+
+impl<T> GameStatic<T> {
+    /// Creates a global named `name`, found by calling `find` and then following `deref`
+    /// pointers.
+    pub const fn new(name: &'static str, deref: usize, find: fn() -> Result<usize>) -> Self {
+        GameStatic {
+            address: LazyAddress::new(name, find),
+            deref,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.address.name()
+    }
+
+    /// Finds the global if it hasn't been found yet, returning the address that its pointers
+    /// (if any) are followed from.
+    pub fn resolve(&self) -> Result<usize> {
+        self.address.resolve()
+    }
+
+    /// Returns a pointer to the global, resolving it if necessary. Returns a null pointer if
+    /// one of the pointers leading to it is null.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the global cannot be found. Call [`GameStatic::resolve`] beforehand to handle
+    /// the error instead.
+    ///
+    /// # Safety
+    /// If the global is reached through pointers, each of them must be null or point to
+    /// readable memory.
+    pub unsafe fn as_ptr(&self) -> *mut T {
+        let mut address = self.address.expect();
+        for _ in 0..self.deref {
+            if address == 0 {
+                break;
+            }
+            address = *(address as *const usize);
+        }
+        address as *mut T
+    }
+
+    /// # Safety
+    /// The global must be initialised, and must not be written to while it is being read.
+    pub unsafe fn read(&self) -> T
+    where
+        T: Copy,
+    {
+        ptr::read(self.as_ptr())
+    }
+
+    /// # Safety
+    /// The global must be initialised, and must not be accessed while it is being written to.
+    pub unsafe fn write(&self, value: T) {
+        ptr::write(self.as_ptr(), value)
+    }
+
+    /// # Safety
+    /// The global must be initialised, and must not be written to while the reference is
+    /// alive.
+    pub unsafe fn get(&self) -> Option<&'static T> {
+        self.as_ptr().as_ref()
+    }
+
+    /// # Safety
+    /// The global must be initialised, and must not be accessed elsewhere while the reference
+    /// is alive.
+    pub unsafe fn get_mut(&self) -> Option<&'static mut T> {
+        self.as_ptr().as_mut()
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    marker::PhantomData,
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{detour::DefaultEngine, detour_binder::DetourBinder, near_allocator::NearAllocation};
+use crate::{
+    error::{Result, UserCallbackResult},
+    function::Function,
+    hook_engine::HookEngine,
+};
+
+/// Every function with at least one hook attached, keyed by address.
+static CHAINS: Mutex<BTreeMap<usize, Chain>> = Mutex::new(BTreeMap::new());
+
+/// The parts of a [`HookEngine`] that a chain uses, so that chains installed by different
+/// engines can be kept together.
+trait ChainEngine: Send {
+    unsafe fn enable(&self) -> Result<()>;
+    unsafe fn disable(&self) -> Result<()>;
+    fn is_enabled(&self) -> bool;
+    fn trampoline(&self) -> *const ();
+}
+
+impl<E: HookEngine> ChainEngine for E {
+    unsafe fn enable(&self) -> Result<()> {
+        HookEngine::enable(self)
+    }
+    unsafe fn disable(&self) -> Result<()> {
+        HookEngine::disable(self)
+    }
+    fn is_enabled(&self) -> bool {
+        HookEngine::is_enabled(self)
+    }
+    fn trampoline(&self) -> *const () {
+        HookEngine::trampoline(self)
+    }
+}
+
+struct Link {
+    function: usize,
+    priority: i32,
+    enabled: AtomicBool,
+    /// The function this link continues to: the next enabled link, or the trampoline.
+    next: Arc<AtomicUsize>,
+}
+
+/// The hooks attached to a single function.
+///
+/// The function is detoured to a dispatch stub that jumps through `head`, so the chain can be
+/// rearranged by updating pointers without touching the detour.
+struct Chain {
+    detour: Box<dyn ChainEngine>,
+    head: *const AtomicUsize,
+    links: Vec<Arc<Link>>,
+    _stub_memory: NearAllocation,
+}
+
+// SAFETY: `head` points into `_stub_memory`, which moves with the chain.
+unsafe impl Send for Chain {}
+
+impl Chain {
+    unsafe fn new<E: HookEngine>(target: usize) -> Result<Chain> {
+        let memory = NearAllocation::new(target, mem::size_of::<usize>() + 6)?;
+        let stub = memory.as_ptr();
+        let dispatch = stub.add(mem::size_of::<usize>());
+        std::ptr::copy_nonoverlapping(dispatch_jump(stub as usize).as_ptr(), dispatch, 6);
+
+        let detour = E::new(target as *const (), dispatch as *const ())?;
+        let head = stub as *const AtomicUsize;
+        (*head).store(detour.trampoline() as usize, Ordering::SeqCst);
+
+        Ok(Chain {
+            detour: Box::new(detour),
+            head,
+            links: vec![],
+            _stub_memory: memory,
+        })
+    }
+
+    fn trampoline(&self) -> usize {
+        self.detour.trampoline() as usize
+    }
+
+    /// Points every link (and the head) at the next enabled link, and enables the detour if
+    /// any link is enabled.
+    fn relink(&self) -> Result<()> {
+        let trampoline = self.trampoline();
+        let mut next = trampoline;
+        for link in self.links.iter().rev() {
+            link.next.store(next, Ordering::SeqCst);
+            if link.enabled.load(Ordering::SeqCst) {
+                next = link.function;
+            }
+        }
+        unsafe {
+            (*self.head).store(next, Ordering::SeqCst);
+            match (next != trampoline, self.detour.is_enabled()) {
+                (true, false) => self.detour.enable()?,
+                (false, true) => self.detour.disable()?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A detour that shares its target function with every other hook on it.
+///
+/// Every [`Detour`](super::detour::Detour) is a chained hook, so hooks from any number of
+/// libraries can be attached to the same function. Hooks on the same function are called in
+/// order of descending priority (hooks with equal priority are called in the order they were
+/// attached). Each hook continues down the chain by calling [`ChainedHook::next`], which ends
+/// at the original function. Hooks can be enabled, disabled and dropped independently of each
+/// other.
+///
+/// The function is detoured once, by the engine `E` of the first hook attached to it.
+pub struct ChainedHook<F: Function, E: HookEngine = DefaultEngine> {
+    target: usize,
+    link: Arc<Link>,
+    name: Option<String>,
+    _function: PhantomData<(F, E)>,
+}
+
+impl<F: Function, E: HookEngine> ChainedHook<F, E> {
+    /// Attaches `detour` to `target` with the given priority. The hook starts disabled.
+    ///
+    /// # Safety
+    ///
+    /// `target` must be a function that can be detoured, and must not be detoured other than
+    /// through chained hooks while any are attached.
+    pub unsafe fn new(target: F, detour: F, priority: i32) -> Result<Self> {
+        Self::with_next_slot(target, detour, priority, Arc::default())
+    }
+
+    /// Like [`ChainedHook::new`], but keeps the address returned by [`ChainedHook::next`] in
+    /// `next`, so that it can be read by code that does not have the hook.
+    pub(crate) unsafe fn with_next_slot(
+        target: F,
+        detour: F,
+        priority: i32,
+        next: Arc<AtomicUsize>,
+    ) -> Result<Self> {
+        let target = target.to_ptr() as usize;
+        let mut chains = CHAINS.lock().unwrap();
+        let chain = match chains.entry(target) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Chain::new::<E>(target)?),
+        };
+
+        let link = Arc::new(Link {
+            function: detour.to_ptr() as usize,
+            priority,
+            enabled: AtomicBool::new(false),
+            next,
+        });
+        link.next.store(chain.trampoline(), Ordering::SeqCst);
+        let index = chain
+            .links
+            .partition_point(|other| other.priority >= priority);
+        chain.links.insert(index, link.clone());
+        chain.relink()?;
+
+        Ok(ChainedHook {
+            target,
+            link,
+            name: None,
+            _function: PhantomData,
+        })
+    }
+
+    /// Names the hook, so it can be addressed by name when added to a
+    /// [`HookLibrary`](super::hook_library::HookLibrary).
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn enable(&self) -> Result<()> {
+        self.set_enabled(true)
+    }
+
+    pub fn disable(&self) -> Result<()> {
+        self.set_enabled(false)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.link.enabled.load(Ordering::SeqCst)
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.link.priority
+    }
+
+    pub fn target(&self) -> F {
+        unsafe { F::from_ptr(self.target as *const ()) }
+    }
+
+    /// Returns the function to call to continue down the chain: the next enabled hook with a
+    /// lower priority, or the original function.
+    pub fn next(&self) -> F {
+        unsafe { F::from_ptr(self.link.next.load(Ordering::SeqCst) as *const ()) }
+    }
+
+    fn set_enabled(&self, enabled: bool) -> Result<()> {
+        let chains = CHAINS.lock().unwrap();
+        let chain = chains
+            .get(&self.target)
+            .expect("chain exists while a hook is attached");
+        let previous = self.link.enabled.swap(enabled, Ordering::SeqCst);
+        chain.relink().inspect_err(|_| {
+            self.link.enabled.store(previous, Ordering::SeqCst);
+            let _ = chain.relink();
+        })
+    }
+}
+
+impl<F: Function, E: HookEngine> DetourBinder for ChainedHook<F, E> {
+    fn enable(&self) -> UserCallbackResult<()> {
+        Ok(ChainedHook::enable(self)?)
+    }
+    fn disable(&self) -> UserCallbackResult<()> {
+        Ok(ChainedHook::disable(self)?)
+    }
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    fn address(&self) -> Option<usize> {
+        Some(self.target)
+    }
+}
+
+impl<F: Function, E: HookEngine> Drop for ChainedHook<F, E> {
+    fn drop(&mut self) {
+        let mut chains = CHAINS.lock().unwrap();
+        let Some(chain) = chains.get_mut(&self.target) else {
+            return;
+        };
+        chain.links.retain(|link| !Arc::ptr_eq(link, &self.link));
+        if chain.links.is_empty() {
+            chains.remove(&self.target);
+        } else {
+            let _ = chain.relink();
+        }
+    }
+}
+
+/// Encodes a jump through the pointer at `slot`, to be placed right after it.
+#[cfg(target_arch = "x86_64")]
+fn dispatch_jump(_slot: usize) -> [u8; 6] {
+    // jmp qword ptr [rip-14]
+    let displacement = -(mem::size_of::<usize>() as i32 + 6);
+    let bytes = displacement.to_le_bytes();
+    [0xFF, 0x25, bytes[0], bytes[1], bytes[2], bytes[3]]
+}
+
+/// Encodes a jump through the pointer at `slot`, to be placed right after it.
+#[cfg(target_arch = "x86")]
+fn dispatch_jump(slot: usize) -> [u8; 6] {
+    // jmp dword ptr [slot]
+    let bytes = (slot as u32).to_le_bytes();
+    [0xFF, 0x25, bytes[0], bytes[1], bytes[2], bytes[3]]
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeMap};
+
+    use super::*;
+    use crate::test_util::ExecutableMemory;
+
+    type Target = extern "sysv64" fn(u64) -> u64;
+
+    /// `mov rax, rdi; add rax, 1; ret`
+    const TARGET: [u8; 8] = [0x48, 0x89, 0xF8, 0x48, 0x83, 0xC0, 0x01, 0xC3];
+
+    thread_local! {
+        /// Where each hook continues to, by the digit it appends.
+        static NEXT: RefCell<BTreeMap<u64, Arc<AtomicUsize>>> = RefCell::default();
+    }
+
+    /// Appends `DIGIT` to the result of the rest of the chain.
+    extern "sysv64" fn hook<const DIGIT: u64>(x: u64) -> u64 {
+        let next = NEXT.with(|next| next.borrow()[&DIGIT].load(Ordering::SeqCst));
+        let next: Target = unsafe { Target::from_ptr(next as *const ()) };
+        next(x) * 10 + DIGIT
+    }
+
+    fn attach<const DIGIT: u64>(target: Target, priority: i32) -> ChainedHook<Target> {
+        let next = Arc::new(AtomicUsize::new(0));
+        NEXT.with(|slots| slots.borrow_mut().insert(DIGIT, next.clone()));
+        unsafe { ChainedHook::with_next_slot(target, hook::<DIGIT>, priority, next).unwrap() }
+    }
+
+    fn target(memory: &ExecutableMemory) -> Target {
+        memory.write(0, &TARGET);
+        unsafe { memory.function(0) }
+    }
+
+    #[test]
+    fn hooks_are_called_in_priority_order() {
+        let memory = ExecutableMemory::new(4096);
+        let target = target(&memory);
+        let a = attach::<2>(target, 0);
+        let b = attach::<3>(target, 5);
+        let c = attach::<4>(target, 0);
+        assert_eq!(target(0), 1);
+
+        for hook in [&a, &b, &c] {
+            hook.enable().unwrap();
+        }
+        // Highest priority first, and equal priorities in the order they were attached.
+        assert_eq!(target(0), 1423);
+        assert_eq!(b.next()(0), 142);
+        assert_eq!(c.next()(0), 1);
+    }
+
+    #[test]
+    fn hooks_can_be_toggled_and_dropped_independently() {
+        let memory = ExecutableMemory::new(4096);
+        let target = target(&memory);
+        let a = attach::<2>(target, 2);
+        let b = attach::<3>(target, 1);
+        let c = attach::<4>(target, 0);
+
+        b.enable().unwrap();
+        assert_eq!(target(0), 13);
+        a.enable().unwrap();
+        c.enable().unwrap();
+        assert_eq!(target(0), 1432);
+
+        b.disable().unwrap();
+        assert_eq!(target(0), 142);
+        assert!(a.is_enabled() && c.is_enabled());
+        b.enable().unwrap();
+        assert_eq!(target(0), 1432);
+
+        drop(b);
+        assert_eq!(target(0), 142);
+
+        a.disable().unwrap();
+        c.disable().unwrap();
+        assert_eq!(target(0), 1);
+        assert_eq!(&memory.bytes(0)[..TARGET.len()], TARGET);
+
+        c.enable().unwrap();
+        assert_eq!(target(0), 14);
+        drop(c);
+        drop(a);
+        assert_eq!(&memory.bytes(0)[..TARGET.len()], TARGET);
+        assert!(CHAINS.lock().unwrap().get(&memory.address()).is_none());
+    }
+}
@@ -1,11 +1,26 @@
-use std::fmt;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use super::{
+    detour::{ClosureDetour, Detour},
     detour_binder::{DetourBinder, RuntimeDetourBinder},
+    hook_chain::ChainedHook,
     patcher::Patcher,
 };
 
-use crate::error::{Error, UserCallbackResult};
+use crate::{
+    closure::ClosureFunction,
+    error::{Error, UserCallbackResult},
+    function::Function,
+    hook_engine::HookEngine,
+    mid_hook::{Context, MidHookCallback},
+};
 
 /// Error type for HookLibrary operations
 #[derive(Debug)]
@@ -14,6 +29,21 @@ pub enum HookLibraryError {
     Standard(Error),
     /// User callback error
     UserCallback(Box<dyn std::error::Error + Send + Sync>),
+    /// Several entries failed to be disabled
+    Multiple(Vec<HookLibraryError>),
+    /// No entry has the given name
+    UnknownEntry(String),
+}
+
+impl HookLibraryError {
+    /// Combines the errors from every entry that failed.
+    fn from_errors(mut errors: Vec<HookLibraryError>) -> Result<(), HookLibraryError> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(HookLibraryError::Multiple(errors)),
+        }
+    }
 }
 
 impl fmt::Display for HookLibraryError {
@@ -21,6 +51,14 @@ impl fmt::Display for HookLibraryError {
         match self {
             HookLibraryError::Standard(e) => write!(f, "{}", e),
             HookLibraryError::UserCallback(e) => write!(f, "user callback error: {}", e),
+            HookLibraryError::UnknownEntry(name) => write!(f, "no hook named `{}`", name),
+            HookLibraryError::Multiple(errors) => {
+                write!(f, "{} errors occurred", errors.len())?;
+                for (index, e) in errors.iter().enumerate() {
+                    write!(f, "{} {}", if index == 0 { ":" } else { ";" }, e)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -30,41 +68,196 @@ impl std::error::Error for HookLibraryError {
         match self {
             HookLibraryError::Standard(e) => e.source(),
             HookLibraryError::UserCallback(e) => e.source(),
+            HookLibraryError::Multiple(_) | HookLibraryError::UnknownEntry(_) => None,
         }
     }
 }
 
-#[allow(clippy::type_complexity)]
+/// The patcher that every library applies its patches and mid hooks through.
+static PATCHES: Mutex<SharedPatcher> = Mutex::new(SharedPatcher {
+    patcher: Patcher::new(),
+    layers: BTreeMap::new(),
+});
+
+static NEXT_LIBRARY_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Identifies an entry across every library, as its library's ID and its index.
+type Owner = (usize, usize);
+
+/// The bytes that an entry wrote to an address.
+struct Layer {
+    owner: Owner,
+    bytes: Vec<u8>,
+    mid_hook: bool,
+}
+
+/// A [`Patcher`] that keeps track of which entry wrote what, so that several libraries can
+/// patch the same address.
+///
+/// The patches at an address are stacked in the order they were applied, with the most recent
+/// one in memory. Removing any of them writes back what the remaining patches would have left
+/// there, on top of the original bytes, which the patcher keeps for as long as any patch
+/// remains.
+struct SharedPatcher {
+    patcher: Patcher,
+    layers: BTreeMap<usize, Vec<Layer>>,
+}
+
+impl SharedPatcher {
+    unsafe fn patch(&mut self, owner: Owner, address: usize, bytes: &[u8]) -> Result<(), Error> {
+        self.patcher.patch(address, bytes)?;
+        self.layers.entry(address).or_default().push(Layer {
+            owner,
+            bytes: bytes.to_owned(),
+            mid_hook: false,
+        });
+        Ok(())
+    }
+
+    /// Mid hooks relocate the code they overwrite, so they can only be placed on code that is
+    /// not patched. Patches may be applied on top of them.
+    unsafe fn mid_hook(
+        &mut self,
+        owner: Owner,
+        address: usize,
+        callback: Arc<MidHookCallback>,
+    ) -> Result<(), Error> {
+        if self.layers.contains_key(&address) {
+            return Err(Error::AddressAlreadyPatched { address });
+        }
+        self.patcher.mid_hook(address, callback)?;
+        let length = self.patcher.original_bytes(address).map_or(0, <[u8]>::len);
+        let bytes = std::slice::from_raw_parts(address as *const u8, length).to_vec();
+        self.layers.insert(
+            address,
+            vec![Layer {
+                owner,
+                bytes,
+                mid_hook: true,
+            }],
+        );
+        Ok(())
+    }
+
+    unsafe fn unpatch(&mut self, owner: Owner, address: usize) -> Result<(), Error> {
+        let unpatch_failed = Error::UnpatchFailed { address };
+        let Some(layers) = self.layers.get_mut(&address) else {
+            return Err(unpatch_failed);
+        };
+        let Some(index) = layers.iter().position(|layer| layer.owner == owner) else {
+            return Err(unpatch_failed);
+        };
+        let removed = layers.remove(index);
+        if layers.is_empty() {
+            self.layers.remove(&address);
+            return self.patcher.unpatch(address).ok_or(unpatch_failed);
+        }
+
+        let mut bytes = self
+            .patcher
+            .original_bytes(address)
+            .ok_or(Error::UnpatchFailed { address })?
+            .to_vec();
+        for layer in layers.iter() {
+            bytes[..layer.bytes.len()].copy_from_slice(&layer.bytes);
+        }
+        if removed.mid_hook {
+            // Unpatching frees the hook's stub; the remaining patches are then reapplied.
+            self.patcher.unpatch(address);
+        }
+        self.patcher.patch(address, &bytes)
+    }
+}
+
+enum EntryKind {
+    StaticBinder(&'static dyn DetourBinder),
+    RuntimeBinder(Box<dyn DetourBinder>),
+    Patch {
+        address: usize,
+        bytes: Vec<u8>,
+    },
+    MidHook {
+        address: usize,
+        callback: Arc<MidHookCallback>,
+    },
+}
+
+struct Entry {
+    name: Option<String>,
+    /// Whether [`HookLibrary::set_enabled`] enables the entry.
+    enabled_by_default: bool,
+    kind: EntryKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryState {
+    Disabled,
+    /// Being enabled or disabled. Binder callbacks run without the states locked, so other
+    /// calls leave the entry alone until the transition is over.
+    Busy,
+    Enabled,
+}
+
+/// What an entry in a [`HookLibrary`] does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEntryKind {
+    Detour,
+    Patch,
+    MidHook,
+}
+
+/// An entry in a [`HookLibrary`], as listed by [`HookLibrary::entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookEntry {
+    /// The entry's name, or a description such as `patch at 0x1000` if it has none. Only
+    /// actual names can be passed to [`HookLibrary::set_entry_enabled`].
+    pub name: String,
+    pub kind: HookEntryKind,
+    /// The hooked or patched address, if it is known (detours only know theirs once enabled).
+    pub address: Option<usize>,
+    pub enabled: bool,
+}
+
+/// A set of detours, patches and mid-function hooks that are enabled and disabled together
+/// (or individually, by name). Dropping the library disables every entry.
+///
+/// Patches and mid-function hooks from every library are applied through one process-wide
+/// [`Patcher`]. Libraries may patch the same address: disabling a patch only restores the bytes
+/// that no other enabled patch has written.
 pub struct HookLibrary {
-    static_binders: Vec<&'static dyn DetourBinder>,
-    runtime_binders: Vec<Box<dyn DetourBinder>>,
-    patches: Vec<(usize, Vec<u8>)>,
+    id: usize,
+    entries: Vec<Entry>,
+    states: Mutex<Vec<EntryState>>,
 }
 impl HookLibrary {
     // builder functions
     pub fn new() -> HookLibrary {
         HookLibrary {
-            static_binders: vec![],
-            runtime_binders: vec![],
-            patches: vec![],
+            id: NEXT_LIBRARY_ID.fetch_add(1, Ordering::Relaxed),
+            entries: vec![],
+            states: Mutex::new(vec![]),
         }
     }
-    pub fn with_static_binder(mut self, binder: &'static dyn DetourBinder) -> Self {
-        self.static_binders.push(binder);
-        self
+    pub fn with_static_binder(self, binder: &'static dyn DetourBinder) -> Self {
+        self.with_entry(EntryKind::StaticBinder(binder))
     }
-    pub fn with_runtime_binder(mut self, binder: Box<dyn DetourBinder>) -> Self {
-        self.runtime_binders.push(binder);
-        self
+    pub fn with_runtime_binder(self, binder: Box<dyn DetourBinder>) -> Self {
+        self.with_entry(EntryKind::RuntimeBinder(binder))
+    }
+    /// Adds a detour, whichever [`HookEngine`] it was created with.
+    pub fn with_detour<F: Function, E: HookEngine>(self, detour: &'static Detour<F, E>) -> Self {
+        self.with_static_binder(detour)
     }
-    pub fn with_detour<F: retour::Function>(
+    /// Adds a detour to a closure, which the library takes ownership of.
+    pub fn with_closure_detour<F: ClosureFunction, E: HookEngine>(
         self,
-        detour: &'static retour::GenericDetour<F>,
+        detour: ClosureDetour<F, E>,
     ) -> Self {
-        self.with_runtime_binder(Box::new(RuntimeDetourBinder {
-            enable: Box::new(|| unsafe { detour.enable().map_err(|e| Box::new(e) as _) }),
-            disable: Box::new(|| unsafe { detour.disable().map_err(|e| Box::new(e) as _) }),
-        }))
+        self.with_runtime_binder(Box::new(detour))
+    }
+    /// Adds a hook that shares its target with other chained hooks; see [`ChainedHook`].
+    pub fn with_chained_hook<F: Function>(self, hook: &'static ChainedHook<F>) -> Self {
+        self.with_static_binder(hook)
     }
     pub fn with_callbacks(
         self,
@@ -76,48 +269,301 @@ impl HookLibrary {
             disable: Box::new(disable),
         }))
     }
-    pub fn with_patch(mut self, address: usize, bytes: &[u8]) -> Self {
-        self.patches.push((address, bytes.to_owned()));
+    pub fn with_patch(self, address: usize, bytes: &[u8]) -> Self {
+        self.with_entry(EntryKind::Patch {
+            address,
+            bytes: bytes.to_owned(),
+        })
+    }
+    /// Adds a patch that writes `value` to `address` in its in-memory (little-endian)
+    /// representation. The original value can be read back through
+    /// [`HookLibrary::original_value`] while the entry is enabled.
+    ///
+    /// # Safety
+    /// `T` must not contain padding bytes, as they are uninitialised.
+    pub unsafe fn with_value_patch<T: Copy>(self, address: usize, value: T) -> Self {
+        self.with_patch(address, crate::util::bytes_of(&value))
+    }
+    /// Adds a mid-function hook that calls `callback` with the registers whenever the
+    /// instruction at `address` is about to execute. See [`Patcher::mid_hook`].
+    pub fn with_mid_hook(
+        self,
+        address: usize,
+        callback: impl Fn(&mut Context) + Send + Sync + 'static,
+    ) -> Self {
+        self.with_entry(EntryKind::MidHook {
+            address,
+            callback: Arc::new(callback),
+        })
+    }
+    /// Adds every patch from a resolved manifest, named after its manifest entry. Patches that
+    /// are not `enabled` in the manifest are left disabled by [`HookLibrary::set_enabled`],
+    /// but can be enabled by name. Entries that failed to resolve are reported in
+    /// `resolution.errors` and are not added.
+    #[cfg(feature = "manifest")]
+    pub fn with_manifest(self, resolution: &crate::manifest::ManifestResolution) -> Self {
+        resolution.patches.iter().fold(self, |library, patch| {
+            let library = library
+                .with_patch(patch.address, &patch.bytes)
+                .named(&patch.name);
+            if patch.enabled {
+                library
+            } else {
+                library.disabled_by_default()
+            }
+        })
+    }
+    /// Names the most recently added entry, overriding the name supplied by its binder (if
+    /// any). Entries are looked up by name in [`HookLibrary::set_entry_enabled`].
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.name = Some(name.into());
+        }
         self
     }
 
-    pub fn set_enabled(
-        &self,
-        patcher: &mut Patcher,
-        enabled: bool,
-    ) -> Result<(), HookLibraryError> {
+    /// Makes [`HookLibrary::set_enabled`] leave the most recently added entry disabled, so that
+    /// it is only enabled through [`HookLibrary::set_entry_enabled`].
+    pub fn disabled_by_default(mut self) -> Self {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.enabled_by_default = false;
+        }
+        self
+    }
+
+    /// Enables every entry in the library that is enabled by default, or disables every entry.
+    /// Entries that are already in the requested state are left alone.
+    ///
+    /// Enabling is all-or-nothing: if any entry fails, the entries enabled by this call are
+    /// disabled again before the error is returned. Disabling attempts every entry, and
+    /// returns all of the errors that occurred.
+    pub fn set_enabled(&self, enabled: bool) -> Result<(), HookLibraryError> {
         if enabled {
-            for binder in self.binders() {
-                binder.enable().map_err(HookLibraryError::UserCallback)?;
+            self.enable_entries().map(|_| ())
+        } else {
+            self.disable_entries(0..self.entries.len())
+        }
+    }
+
+    /// Whether every entry in the library that is enabled by default is enabled.
+    pub fn is_enabled(&self) -> bool {
+        let states = self.states.lock().unwrap();
+        self.entries
+            .iter()
+            .zip(states.iter())
+            .filter(|(entry, _)| entry.enabled_by_default)
+            .all(|(_, state)| *state == EntryState::Enabled)
+    }
+
+    /// Enables or disables the first entry with the given name, as set with
+    /// [`HookLibrary::named`] or supplied by the entry's binder.
+    pub fn set_entry_enabled(&self, name: &str, enabled: bool) -> Result<(), HookLibraryError> {
+        let index = self
+            .find(name)
+            .ok_or_else(|| HookLibraryError::UnknownEntry(name.to_owned()))?;
+        if !enabled {
+            return self.disable_entries(index..index + 1);
+        }
+
+        if self.begin_transition(index, EntryState::Disabled) {
+            let result = self.entries[index].enable((self.id, index));
+            self.end_transition(
+                index,
+                match result {
+                    Ok(()) => EntryState::Enabled,
+                    Err(_) => EntryState::Disabled,
+                },
+            );
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Whether the first entry with the given name is enabled, or `None` if there is no such
+    /// entry.
+    pub fn is_entry_enabled(&self, name: &str) -> Option<bool> {
+        let index = self.find(name)?;
+        let states = self.states.lock().unwrap();
+        Some(states[index] == EntryState::Enabled)
+    }
+
+    /// Returns the value that the first patch with the given name replaced, if it is enabled
+    /// and patched at least `size_of::<T>()` bytes. See [`HookLibrary::with_value_patch`].
+    pub fn original_value<T: Copy>(&self, name: &str) -> Option<T> {
+        let index = self.find(name)?;
+        let EntryKind::Patch { address, .. } = self.entries[index].kind else {
+            return None;
+        };
+        if self.states.lock().unwrap()[index] != EntryState::Enabled {
+            return None;
+        }
+        PATCHES.lock().unwrap().patcher.original(address)
+    }
+
+    /// Lists every entry in the library, in the order they were added.
+    pub fn entries(&self) -> Vec<HookEntry> {
+        let states = self.states.lock().unwrap();
+        self.entries
+            .iter()
+            .zip(states.iter())
+            .enumerate()
+            .map(|(index, (entry, state))| HookEntry {
+                name: entry.name(index),
+                kind: entry.kind(),
+                address: entry.address(),
+                enabled: *state == EntryState::Enabled,
+            })
+            .collect()
+    }
+}
+impl HookLibrary {
+    fn with_entry(mut self, kind: EntryKind) -> Self {
+        self.entries.push(Entry {
+            name: None,
+            enabled_by_default: true,
+            kind,
+        });
+        self.states.get_mut().unwrap().push(EntryState::Disabled);
+        self
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.explicit_name() == Some(name))
+    }
+
+    /// Enables every disabled entry that is enabled by default, rolling back on failure.
+    /// Returns the indices of the entries that were enabled.
+    fn enable_entries(&self) -> Result<Vec<usize>, HookLibraryError> {
+        let mut enabled = vec![];
+        for (index, entry) in self.entries.iter().enumerate() {
+            if !entry.enabled_by_default || !self.begin_transition(index, EntryState::Disabled) {
+                continue;
             }
-            for (address, patch) in &self.patches {
-                unsafe {
-                    patcher.patch(*address, patch);
+            match entry.enable((self.id, index)) {
+                Ok(()) => {
+                    self.end_transition(index, EntryState::Enabled);
+                    enabled.push(index);
                 }
-            }
-        } else {
-            for (address, _) in &self.patches {
-                unsafe {
-                    patcher.unpatch(*address).ok_or_else(|| {
-                        HookLibraryError::Standard(Error::UnpatchFailed { address: *address })
-                    })?;
+                Err(e) => {
+                    self.end_transition(index, EntryState::Disabled);
+                    let _ = self.disable_entries(enabled.into_iter());
+                    return Err(e);
                 }
             }
-            for binder in self.binders() {
-                binder.disable().map_err(HookLibraryError::UserCallback)?;
+        }
+        Ok(enabled)
+    }
+
+    /// Disables every enabled entry in `indices`, even if some of them fail.
+    fn disable_entries(
+        &self,
+        indices: impl DoubleEndedIterator<Item = usize>,
+    ) -> Result<(), HookLibraryError> {
+        let mut errors = vec![];
+        for index in indices.rev() {
+            if !self.begin_transition(index, EntryState::Enabled) {
+                continue;
+            }
+            if let Err(e) = self.entries[index].disable((self.id, index)) {
+                errors.push(e);
             }
+            self.end_transition(index, EntryState::Disabled);
         }
-        Ok(())
+        HookLibraryError::from_errors(errors)
+    }
+
+    /// Marks the entry as busy if it is in the `from` state, returning whether it was, so that
+    /// its enable or disable can run without the states locked.
+    fn begin_transition(&self, index: usize, from: EntryState) -> bool {
+        let mut states = self.states.lock().unwrap();
+        if states[index] != from {
+            return false;
+        }
+        states[index] = EntryState::Busy;
+        true
+    }
+
+    fn end_transition(&self, index: usize, to: EntryState) {
+        self.states.lock().unwrap()[index] = to;
     }
 }
-impl HookLibrary {
-    fn binders(&self) -> impl Iterator<Item = &dyn DetourBinder> {
-        self.static_binders
-            .iter()
-            .map(|b| *b as &dyn DetourBinder)
-            .chain(self.runtime_binders.iter().map(|b| b.as_ref()))
+
+impl Entry {
+    fn binder(&self) -> Option<&dyn DetourBinder> {
+        match &self.kind {
+            EntryKind::StaticBinder(binder) => Some(*binder),
+            EntryKind::RuntimeBinder(binder) => Some(binder.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// The name given with [`HookLibrary::named`], or else the one supplied by the binder.
+    fn explicit_name(&self) -> Option<&str> {
+        self.name
+            .as_deref()
+            .or_else(|| self.binder().and_then(|binder| binder.name()))
+    }
+
+    fn name(&self, index: usize) -> String {
+        if let Some(name) = self.explicit_name() {
+            return name.to_owned();
+        }
+        match self.kind {
+            EntryKind::Patch { address, .. } => format!("patch at 0x{:x}", address),
+            EntryKind::MidHook { address, .. } => format!("mid hook at 0x{:x}", address),
+            _ => format!("hook {}", index),
+        }
+    }
+
+    fn kind(&self) -> HookEntryKind {
+        match self.kind {
+            EntryKind::StaticBinder(_) | EntryKind::RuntimeBinder(_) => HookEntryKind::Detour,
+            EntryKind::Patch { .. } => HookEntryKind::Patch,
+            EntryKind::MidHook { .. } => HookEntryKind::MidHook,
+        }
+    }
+
+    fn address(&self) -> Option<usize> {
+        match self.kind {
+            EntryKind::Patch { address, .. } | EntryKind::MidHook { address, .. } => Some(address),
+            _ => self.binder().and_then(|binder| binder.address()),
+        }
+    }
+
+    fn enable(&self, owner: Owner) -> Result<(), HookLibraryError> {
+        match &self.kind {
+            EntryKind::Patch { address, bytes } => {
+                let mut patches = PATCHES.lock().unwrap();
+                unsafe { patches.patch(owner, *address, bytes) }.map_err(HookLibraryError::Standard)
+            }
+            EntryKind::MidHook { address, callback } => {
+                let mut patches = PATCHES.lock().unwrap();
+                unsafe { patches.mid_hook(owner, *address, callback.clone()) }
+                    .map_err(HookLibraryError::Standard)
+            }
+            _ => {
+                let binder = self.binder().expect("entry is a binder");
+                binder.enable().map_err(HookLibraryError::UserCallback)
+            }
+        }
+    }
+
+    fn disable(&self, owner: Owner) -> Result<(), HookLibraryError> {
+        match &self.kind {
+            EntryKind::Patch { address, .. } | EntryKind::MidHook { address, .. } => {
+                unsafe { PATCHES.lock().unwrap().unpatch(owner, *address) }
+                    .map_err(HookLibraryError::Standard)
+            }
+            _ => {
+                let binder = self.binder().expect("entry is a binder");
+                binder.disable().map_err(HookLibraryError::UserCallback)
+            }
+        }
     }
 }
+
 impl Default for HookLibrary {
     fn default() -> Self {
         Self::new()
@@ -125,9 +571,7 @@ impl Default for HookLibrary {
 }
 impl Drop for HookLibrary {
     fn drop(&mut self) {
-        for binder in self.binders() {
-            let _ = binder.disable();
-        }
+        let _ = self.disable_entries(0..self.entries.len());
     }
 }
 
@@ -136,18 +580,91 @@ impl HookLibraries {
     pub fn new(libraries: impl Into<Vec<HookLibrary>>) -> HookLibraries {
         HookLibraries(libraries.into())
     }
-    pub fn set_enabled(
-        &self,
-        patcher: &mut Patcher,
-        enabled: bool,
-    ) -> Result<(), HookLibraryError> {
+    /// Enables or disables every library, with the same guarantees as
+    /// [`HookLibrary::set_enabled`]: if a library fails to enable, the entries enabled by this
+    /// call in the other libraries are disabled again.
+    pub fn set_enabled(&self, enabled: bool) -> Result<(), HookLibraryError> {
+        if !enabled {
+            let errors = self
+                .0
+                .iter()
+                .rev()
+                .filter_map(|library| library.set_enabled(false).err())
+                .collect();
+            return HookLibraryError::from_errors(errors);
+        }
+
+        let mut enabled_entries = vec![];
         for library in &self.0 {
-            library.set_enabled(patcher, enabled)?;
+            match library.enable_entries() {
+                Ok(indices) => enabled_entries.push((library, indices)),
+                Err(e) => {
+                    for (library, indices) in enabled_entries.into_iter().rev() {
+                        let _ = library.disable_entries(indices.into_iter());
+                    }
+                    return Err(e);
+                }
+            }
         }
         Ok(())
     }
-    pub fn enable(self, patcher: &mut Patcher) -> Result<Self, HookLibraryError> {
-        self.set_enabled(patcher, true)?;
+    pub fn enable(self) -> Result<Self, HookLibraryError> {
+        self.set_enabled(true)?;
         Ok(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabling_a_lower_patch_keeps_the_one_above() {
+        let mut buffer = Box::new([0u8; 4]);
+        let address = buffer.as_mut_ptr() as usize;
+        let a = HookLibrary::new().with_patch(address, &[1, 1, 1]);
+        let b = HookLibrary::new().with_patch(address, &[2]);
+
+        a.set_enabled(true).unwrap();
+        b.set_enabled(true).unwrap();
+        assert_eq!(*buffer, [2, 1, 1, 0]);
+        a.set_enabled(false).unwrap();
+        assert_eq!(*buffer, [2, 0, 0, 0]);
+        drop(b);
+        assert_eq!(*buffer, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn disabling_the_top_patch_restores_the_one_below() {
+        let mut buffer = Box::new([0u8; 4]);
+        let address = buffer.as_mut_ptr() as usize;
+        let a = HookLibrary::new().with_patch(address, &[1]);
+        let b = HookLibrary::new().with_patch(address, &[2, 2]);
+
+        a.set_enabled(true).unwrap();
+        b.set_enabled(true).unwrap();
+        assert_eq!(*buffer, [2, 2, 0, 0]);
+        b.set_enabled(false).unwrap();
+        assert_eq!(*buffer, [1, 0, 0, 0]);
+        a.set_enabled(false).unwrap();
+        assert_eq!(*buffer, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn original_value_is_shared_between_libraries() {
+        let mut value = Box::new(7u32);
+        let address = &mut *value as *mut u32 as usize;
+        let a = unsafe { HookLibrary::new().with_value_patch(address, 8u32) }.named("a");
+        let b = unsafe { HookLibrary::new().with_value_patch(address, 9u32) }.named("b");
+
+        a.set_enabled(true).unwrap();
+        b.set_enabled(true).unwrap();
+        assert_eq!(*value, 9);
+        assert_eq!(a.original_value::<u32>("a"), Some(7));
+        assert_eq!(b.original_value::<u32>("b"), Some(7));
+        a.set_enabled(false).unwrap();
+        assert_eq!(*value, 9);
+        b.set_enabled(false).unwrap();
+        assert_eq!(*value, 7);
+    }
+}
@@ -1,9 +1,17 @@
+pub mod detour;
 pub mod detour_binder;
+pub mod game_fn;
+pub mod game_static;
 pub mod hook_library;
 pub mod module;
+pub mod vtable;
 
+mod hook_chain;
+mod near_allocator;
 mod patcher;
 mod thread_suspender;
 
+pub use hook_chain::ChainedHook;
+pub use near_allocator::NearAllocator;
 pub use patcher::Patcher;
 pub use thread_suspender::ThreadSuspender;
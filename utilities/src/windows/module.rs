@@ -1,15 +1,36 @@
-use std::{collections, ffi::OsString, io, mem, os::windows::ffi::OsStringExt, path::Path, slice};
-
-use windows::Win32::{
-    Foundation::HMODULE,
-    System::{
-        LibraryLoader::GetModuleFileNameW,
-        ProcessStatus::{K32EnumProcessModules, K32GetModuleInformation, MODULEINFO},
-        Threading::GetCurrentProcess,
+use std::{
+    collections::{self, BTreeMap},
+    ffi::OsString,
+    io, iter, mem,
+    os::windows::ffi::OsStringExt,
+    path::Path,
+    slice,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock},
+};
+
+use windows::{
+    core::{PCSTR, PCWSTR},
+    Win32::{
+        Foundation::HMODULE,
+        System::{
+            LibraryLoader::{GetModuleFileNameW, GetModuleHandleW, GetProcAddress},
+            ProcessStatus::{K32EnumProcessModules, K32GetModuleInformation, MODULEINFO},
+            Threading::GetCurrentProcess,
+        },
     },
 };
 
-use crate::error::{Error, Result};
+use crate::error::{Error, Result, WindowsError};
+
+/// Modules used through [`Module::with_shared`], keyed by lowercase name (`None` for the main
+/// executable), so that their scan caches are shared.
+static SHARED_MODULES: Mutex<BTreeMap<Option<String>, SharedModule>> = Mutex::new(BTreeMap::new());
+
+struct SharedModule(Module);
+
+// SAFETY: the module's pointers refer to an image that stays loaded for the lifetime of the
+// process, and it is only accessed through `SHARED_MODULES`.
+unsafe impl Send for SharedModule {}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum CacheKey {
@@ -24,6 +45,10 @@ struct SerializedCache {
     entries: Vec<(CacheKey, usize)>,
 }
 
+/// A module loaded in the process.
+///
+/// Clones share the image backup and the scan cache, so copying a module is cheap and scans
+/// through any of the copies are cached for all of them.
 #[derive(Debug, Clone)]
 pub struct Module {
     handle: HMODULE,
@@ -31,8 +56,8 @@ pub struct Module {
     pub base: *mut u8,
     _entry_point: *mut u8,
     image_size: u32,
-    image_backup: Vec<u8>,
-    cache: collections::HashMap<CacheKey, usize>,
+    image_backup: Arc<Vec<u8>>,
+    cache: Arc<RwLock<collections::HashMap<CacheKey, usize>>>,
 }
 
 impl Module {
@@ -58,9 +83,77 @@ impl Module {
             base: mod_info.lpBaseOfDll as *mut u8,
             _entry_point: mod_info.EntryPoint as *mut u8,
             image_size: mod_info.SizeOfImage,
-            image_backup: vec![],
-            cache: collections::HashMap::new(),
+            image_backup: Arc::default(),
+            cache: Arc::default(),
+        }
+    }
+
+    /// Finds a loaded module by name (e.g. `engine.dll`), or the main executable if `name` is
+    /// `None`.
+    pub fn find(name: Option<&str>) -> Result<Module> {
+        let wide_name: Option<Vec<u16>> =
+            name.map(|name| name.encode_utf16().chain(iter::once(0)).collect());
+        let handle = unsafe {
+            GetModuleHandleW(
+                wide_name
+                    .as_ref()
+                    .map_or(PCWSTR::null(), |name| PCWSTR(name.as_ptr())),
+            )
         }
+        .map_err(|source| WindowsError::ModuleNotFound {
+            name: name.unwrap_or("main executable").to_owned(),
+            source,
+        })?;
+        Ok(Module::from_handle(handle))
+    }
+
+    /// Calls `f` with a module that is shared across the process, finding it with
+    /// [`Module::find`] on first use. Scans through the shared module are cached for every
+    /// caller.
+    ///
+    /// `f` runs on a copy of the shared module, without holding any lock, so it may itself use
+    /// shared modules. The copy shares the module's scan cache, which is updated as `f` scans.
+    pub fn with_shared<T>(
+        name: Option<&str>,
+        f: impl FnOnce(&mut Module) -> Result<T>,
+    ) -> Result<T> {
+        let key = name.map(str::to_lowercase);
+        let cached = Self::shared_modules()
+            .get(&key)
+            .map(|module| module.0.clone());
+        let mut module = match cached {
+            Some(module) => module,
+            None => Module::find(name)?,
+        };
+
+        let result = f(&mut module);
+
+        let mut modules = Self::shared_modules();
+        match modules.entry(key) {
+            collections::btree_map::Entry::Occupied(mut entry) => {
+                let shared = &mut entry.get_mut().0;
+                // Another caller found the module at the same time; keep its cache.
+                if !Arc::ptr_eq(&shared.cache, &module.cache) {
+                    let scanned = mem::take(&mut *write_cache(&module.cache));
+                    write_cache(&shared.cache).extend(scanned);
+                }
+                if shared.image_backup.is_empty() {
+                    shared.image_backup = module.image_backup;
+                }
+            }
+            collections::btree_map::Entry::Vacant(entry) => {
+                entry.insert(SharedModule(module));
+            }
+        }
+        result
+    }
+
+    /// Locks the shared modules. The lock is never held while user code runs, so it is only
+    /// poisoned by a panic that left the map itself intact.
+    fn shared_modules() -> MutexGuard<'static, BTreeMap<Option<String>, SharedModule>> {
+        SHARED_MODULES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
     }
 
     pub fn get_all() -> impl Iterator<Item = Module> {
@@ -90,7 +183,7 @@ impl Module {
 
     #[allow(dead_code)]
     pub fn backup_image(&mut self) {
-        self.image_backup = self.as_bytes_from_memory().to_vec();
+        self.image_backup = Arc::new(self.as_bytes_from_memory().to_vec());
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -132,8 +225,8 @@ impl Module {
     }
 
     pub fn scan(&mut self, pattern: &str) -> Result<*mut u8> {
-        let offset = if let Some(offset) = self.cache.get(&CacheKey::Regular(pattern.to_owned())) {
-            *offset
+        let offset = if let Some(offset) = self.cached(&CacheKey::Regular(pattern.to_owned())) {
+            offset
         } else {
             patternscan::scan_first_match(io::Cursor::new(self.as_bytes()), pattern)?.ok_or(
                 Error::PatternScanFailed {
@@ -142,18 +235,16 @@ impl Module {
             )?
         };
 
-        self.cache
-            .insert(CacheKey::Regular(pattern.to_owned()), offset);
+        self.insert_cached(CacheKey::Regular(pattern.to_owned()), offset);
 
         Ok(self.rel_to_abs_addr(offset))
     }
 
     pub fn scan_for_relative_callsite(&self, pattern: &str, addr_offset: usize) -> Result<*mut u8> {
-        let offset = if let Some(offset) = self
-            .cache
-            .get(&CacheKey::RelativeCallsite(pattern.to_owned()))
+        let offset = if let Some(offset) =
+            self.cached(&CacheKey::RelativeCallsite(pattern.to_owned()))
         {
-            *offset
+            offset
         } else {
             let offset = patternscan::scan_first_match(io::Cursor::new(self.as_bytes()), pattern)?
                 .ok_or(Error::PatternScanFailed {
@@ -174,11 +265,10 @@ impl Module {
     pub fn scan_after_ptr(&mut self, base: *const u8, pattern: &str) -> Result<*mut u8> {
         let base_offset = self.abs_to_rel_addr(base) as usize;
 
-        let offset = if let Some(offset) = self
-            .cache
-            .get(&CacheKey::AfterPtr(pattern.to_owned(), base_offset))
+        let offset = if let Some(offset) =
+            self.cached(&CacheKey::AfterPtr(pattern.to_owned(), base_offset))
         {
-            *offset
+            offset
         } else {
             let slice = &self.as_bytes()[base_offset..];
 
@@ -190,12 +280,35 @@ impl Module {
             base_offset + offset_from_base
         };
 
-        self.cache
-            .insert(CacheKey::AfterPtr(pattern.to_owned(), base_offset), offset);
+        self.insert_cached(CacheKey::AfterPtr(pattern.to_owned(), base_offset), offset);
 
         Ok(self.rel_to_abs_addr(offset))
     }
 
+    fn cached(&self, key: &CacheKey) -> Option<usize> {
+        self.cache
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+            .copied()
+    }
+
+    fn insert_cached(&self, key: CacheKey, offset: usize) {
+        write_cache(&self.cache).insert(key, offset);
+    }
+
+    /// Finds the function exported by the module under `name`.
+    pub fn export(&self, name: &str) -> Result<*mut u8> {
+        let not_found = || Error::ExportNotFound {
+            module: self.filename(),
+            name: name.to_owned(),
+        };
+        let c_name = std::ffi::CString::new(name).map_err(|_| not_found())?;
+        unsafe { GetProcAddress(self.handle, PCSTR(c_name.as_ptr() as *const u8)) }
+            .map(|function| function as *mut u8)
+            .ok_or_else(not_found)
+    }
+
     pub fn path(&self) -> Option<&Path> {
         self.path.as_ref().map(Path::new)
     }
@@ -247,3 +360,20 @@ impl Module {
         }
     }
 }
+
+/// Locks a scan cache for writing. Entries are inserted whole, so a poisoned cache is intact.
+fn write_cache(
+    cache: &RwLock<collections::HashMap<CacheKey, usize>>,
+) -> std::sync::RwLockWriteGuard<'_, collections::HashMap<CacheKey, usize>> {
+    cache.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(feature = "manifest")]
+impl crate::manifest::Image for Module {
+    fn base(&self) -> usize {
+        self.base as usize
+    }
+    fn bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
@@ -0,0 +1,391 @@
+use std::{collections::BTreeMap, mem, sync::Mutex};
+
+use windows::Win32::System::{
+    Memory::{
+        VirtualAlloc, VirtualFree, VirtualQuery, MEMORY_BASIC_INFORMATION, MEM_COMMIT, MEM_FREE,
+        MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE_READWRITE,
+    },
+    SystemInformation::{GetSystemInfo, SYSTEM_INFO},
+};
+
+use crate::error::{Error, Result};
+
+/// The furthest distance a block may be placed from the address it was requested near.
+///
+/// This is slightly less than `i32::MAX` so that anything within the block is still reachable
+/// with a rel32 displacement from the requesting instruction.
+const MAX_DISTANCE: usize = 0x7FF0_0000;
+
+/// Alignment of each allocation within a block.
+const ALLOCATION_ALIGNMENT: usize = 16;
+
+/// The allocator shared by every hook in the process, so that their code is packed into as
+/// few blocks as possible.
+static SHARED: Mutex<NearAllocator> = Mutex::new(NearAllocator::new());
+
+struct Block {
+    base: usize,
+    size: usize,
+    used: usize,
+    /// Freed ranges below `used`, as sorted, non-adjacent `(offset, length)` pairs.
+    free: Vec<(usize, usize)>,
+}
+
+impl Block {
+    fn is_within_range(&self, address: usize) -> bool {
+        self.base.abs_diff(address) <= MAX_DISTANCE
+            && (self.base + self.size).abs_diff(address) <= MAX_DISTANCE
+    }
+
+    fn contains(&self, address: usize) -> bool {
+        (self.base..self.base + self.size).contains(&address)
+    }
+
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        let size = size.next_multiple_of(ALLOCATION_ALIGNMENT);
+        if let Some(index) = self.free.iter().position(|(_, length)| *length >= size) {
+            let (offset, length) = &mut self.free[index];
+            let address = self.base + *offset;
+            *offset += size;
+            *length -= size;
+            if *length == 0 {
+                self.free.remove(index);
+            }
+            return Some(address);
+        }
+
+        if self.used + size > self.size {
+            return None;
+        }
+        let offset = self.used;
+        self.used += size;
+        Some(self.base + offset)
+    }
+
+    fn free(&mut self, address: usize, size: usize) {
+        let mut offset = address - self.base;
+        let mut length = size.next_multiple_of(ALLOCATION_ALIGNMENT);
+        let index = self.free.partition_point(|(other, _)| *other < offset);
+        if let Some(&(next, next_length)) = self.free.get(index) {
+            if offset + length == next {
+                length += next_length;
+                self.free.remove(index);
+            }
+        }
+        if let Some((previous, previous_length)) = index.checked_sub(1).map(|i| self.free[i]) {
+            if previous + previous_length == offset {
+                offset = previous;
+                length += previous_length;
+                self.free.remove(index - 1);
+            }
+        }
+
+        if offset + length == self.used {
+            self.used = offset;
+        } else {
+            let index = self.free.partition_point(|(other, _)| *other < offset);
+            self.free.insert(index, (offset, length));
+        }
+    }
+}
+
+/// Allocates executable memory within rel32 range of a given address.
+///
+/// Memory is reserved in blocks of the system allocation granularity and handed out in
+/// small aligned chunks. A block is released once everything allocated from it has been freed,
+/// and all blocks are released when the allocator is dropped, so anything that jumps into the
+/// allocated memory must be removed first.
+pub struct NearAllocator {
+    blocks: Vec<Block>,
+    jump_stubs: BTreeMap<usize, Vec<usize>>,
+}
+
+#[allow(clippy::missing_safety_doc)]
+impl NearAllocator {
+    pub const fn new() -> NearAllocator {
+        NearAllocator {
+            blocks: vec![],
+            jump_stubs: BTreeMap::new(),
+        }
+    }
+
+    /// Allocates `size` bytes of executable memory that can be reached from `near` with a
+    /// rel32 displacement.
+    ///
+    /// # Safety
+    ///
+    /// The returned memory is uninitialised and is freed when the allocator is dropped.
+    pub unsafe fn allocate(&mut self, near: usize, size: usize) -> Result<*mut u8> {
+        if let Some(address) = self
+            .blocks
+            .iter_mut()
+            .filter(|block| block.is_within_range(near))
+            .find_map(|block| block.allocate(size))
+        {
+            return Ok(address as *mut u8);
+        }
+
+        let mut block = Self::allocate_block(near, size)?;
+        let address = block
+            .allocate(size)
+            .ok_or(Error::NearAllocationFailed { address: near })?;
+        self.blocks.push(block);
+
+        Ok(address as *mut u8)
+    }
+
+    /// Frees `size` bytes at `address`, which must have been returned by
+    /// [`NearAllocator::allocate`] with the same size.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may execute or refer to the memory afterwards.
+    pub unsafe fn free(&mut self, address: *mut u8, size: usize) {
+        let address = address as usize;
+        let Some(index) = self.blocks.iter().position(|block| block.contains(address)) else {
+            return;
+        };
+        let block = &mut self.blocks[index];
+        block.free(address, size);
+        if block.used == 0 {
+            let _ = VirtualFree(block.base as _, 0, MEM_RELEASE);
+            self.blocks.remove(index);
+        }
+    }
+
+    /// Returns the address of a stub within rel32 range of `near` that jumps to `destination`.
+    ///
+    /// Stubs are reused for the same destination whenever an existing stub is in range.
+    ///
+    /// # Safety
+    ///
+    /// The stub is freed when the allocator is dropped.
+    pub unsafe fn jump_stub(&mut self, near: usize, destination: usize) -> Result<usize> {
+        if let Some(stub) = self.jump_stubs.get(&destination).and_then(|stubs| {
+            stubs
+                .iter()
+                .find(|stub| stub.abs_diff(near) <= MAX_DISTANCE)
+        }) {
+            return Ok(*stub);
+        }
+
+        let bytes = absolute_jump(destination);
+        let stub = self.allocate(near, bytes.len())?;
+        std::slice::from_raw_parts_mut(stub, bytes.len()).copy_from_slice(&bytes);
+
+        let stub = stub as usize;
+        self.jump_stubs.entry(destination).or_default().push(stub);
+        Ok(stub)
+    }
+
+    unsafe fn allocate_block(near: usize, size: usize) -> Result<Block> {
+        let mut system_info = SYSTEM_INFO::default();
+        GetSystemInfo(&mut system_info);
+
+        let granularity = system_info.dwAllocationGranularity as usize;
+        let block_size = size.next_multiple_of(granularity);
+        let min_address = (system_info.lpMinimumApplicationAddress as usize)
+            .max(near.saturating_sub(MAX_DISTANCE))
+            .next_multiple_of(granularity);
+        let max_address = (system_info.lpMaximumApplicationAddress as usize)
+            .min(near.saturating_add(MAX_DISTANCE))
+            .saturating_sub(block_size);
+
+        // Search outwards from `near` so that the block ends up as close as possible.
+        let start = near - near % granularity;
+        let below = (0..)
+            .map_while(|i: usize| start.checked_sub(i * granularity))
+            .take_while(|candidate| *candidate >= min_address);
+        let above = (1..)
+            .map_while(|i: usize| start.checked_add(i * granularity))
+            .take_while(|candidate| *candidate <= max_address);
+
+        for candidate in interleave(below, above) {
+            let mut info = MEMORY_BASIC_INFORMATION::default();
+            let queried = VirtualQuery(
+                Some(candidate as _),
+                &mut info,
+                mem::size_of::<MEMORY_BASIC_INFORMATION>(),
+            );
+            if queried == 0 || info.State != MEM_FREE {
+                continue;
+            }
+            if info.BaseAddress as usize + info.RegionSize < candidate + block_size {
+                continue;
+            }
+
+            let base = VirtualAlloc(
+                Some(candidate as _),
+                block_size,
+                MEM_COMMIT | MEM_RESERVE,
+                PAGE_EXECUTE_READWRITE,
+            );
+            if !base.is_null() {
+                return Ok(Block {
+                    base: base as usize,
+                    size: block_size,
+                    used: 0,
+                    free: vec![],
+                });
+            }
+        }
+
+        Err(Error::NearAllocationFailed { address: near })
+    }
+}
+
+impl Default for NearAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for NearAllocator {
+    fn drop(&mut self) {
+        for block in &self.blocks {
+            unsafe {
+                let _ = VirtualFree(block.base as _, 0, MEM_RELEASE);
+            }
+        }
+    }
+}
+
+/// Memory allocated from the process-wide [`NearAllocator`], which is freed when dropped.
+pub(crate) struct NearAllocation {
+    address: *mut u8,
+    size: usize,
+}
+
+// SAFETY: the allocation is only a range of addresses; the shared allocator is locked to free it.
+unsafe impl Send for NearAllocation {}
+unsafe impl Sync for NearAllocation {}
+
+impl NearAllocation {
+    /// Allocates `size` bytes of executable memory that can be reached from `near` with a
+    /// rel32 displacement. The memory is uninitialised.
+    pub(crate) fn new(near: usize, size: usize) -> Result<NearAllocation> {
+        let address = unsafe { SHARED.lock().unwrap().allocate(near, size)? };
+        Ok(NearAllocation { address, size })
+    }
+
+    /// Allocates a stub within rel32 range of `near` that jumps to `destination`.
+    pub(crate) fn jump_stub(near: usize, destination: usize) -> Result<NearAllocation> {
+        let bytes = absolute_jump(destination);
+        let stub = NearAllocation::new(near, bytes.len())?;
+        unsafe { std::slice::from_raw_parts_mut(stub.address, bytes.len()) }
+            .copy_from_slice(&bytes);
+        Ok(stub)
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.address
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for NearAllocation {
+    fn drop(&mut self) {
+        unsafe { SHARED.lock().unwrap().free(self.address, self.size) }
+    }
+}
+
+/// Encodes a jump to `destination` that does not depend on where it is placed.
+#[cfg(target_arch = "x86_64")]
+fn absolute_jump(destination: usize) -> Vec<u8> {
+    // jmp qword ptr [rip+0]; dq destination
+    let mut bytes = vec![0xFF, 0x25, 0x00, 0x00, 0x00, 0x00];
+    bytes.extend_from_slice(&(destination as u64).to_le_bytes());
+    bytes
+}
+
+/// Encodes a jump to `destination` that does not depend on where it is placed.
+#[cfg(target_arch = "x86")]
+fn absolute_jump(destination: usize) -> Vec<u8> {
+    // push destination; ret
+    let mut bytes = vec![0x68];
+    bytes.extend_from_slice(&(destination as u32).to_le_bytes());
+    bytes.push(0xC3);
+    bytes
+}
+
+fn interleave<T>(
+    a: impl Iterator<Item = T>,
+    b: impl Iterator<Item = T>,
+) -> impl Iterator<Item = T> {
+    let (mut a, mut b) = (a.fuse(), b.fuse());
+    let mut take_from_a = false;
+    std::iter::from_fn(move || {
+        take_from_a = !take_from_a;
+        if take_from_a {
+            a.next().or_else(|| b.next())
+        } else {
+            b.next().or_else(|| a.next())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block() -> Block {
+        Block {
+            base: 0x10000,
+            size: 0x100,
+            used: 0,
+            free: vec![],
+        }
+    }
+
+    #[test]
+    fn allocations_are_aligned() {
+        let mut block = block();
+        assert_eq!(block.allocate(5), Some(0x10000));
+        assert_eq!(block.allocate(17), Some(0x10010));
+        assert_eq!(block.allocate(1), Some(0x10030));
+        assert_eq!(block.used, 0x40);
+        assert_eq!(block.allocate(0xD0), None);
+    }
+
+    #[test]
+    fn freed_memory_is_reused() {
+        let mut block = block();
+        let a = block.allocate(0x10).unwrap();
+        let b = block.allocate(0x20).unwrap();
+        let _c = block.allocate(0x10).unwrap();
+        block.free(b, 0x20);
+        assert_eq!(block.free, [(0x10, 0x20)]);
+        assert_eq!(block.allocate(0x10), Some(b));
+        assert_eq!(block.allocate(0x10), Some(b + 0x10));
+        assert!(block.free.is_empty());
+        block.free(a, 0x10);
+        assert_eq!(block.allocate(0x20), Some(0x10040));
+    }
+
+    #[test]
+    fn adjacent_frees_are_merged() {
+        let mut block = block();
+        let a = block.allocate(0x10).unwrap();
+        let b = block.allocate(0x10).unwrap();
+        let c = block.allocate(0x10).unwrap();
+        let _d = block.allocate(0x10).unwrap();
+        block.free(a, 0x10);
+        block.free(c, 0x10);
+        block.free(b, 0x10);
+        assert_eq!(block.free, [(0, 0x30)]);
+    }
+
+    #[test]
+    fn freeing_the_end_shrinks_the_block() {
+        let mut block = block();
+        let a = block.allocate(0x10).unwrap();
+        let b = block.allocate(0x10).unwrap();
+        block.free(a, 0x10);
+        block.free(b, 0x10);
+        assert_eq!(block.used, 0);
+        assert!(block.free.is_empty());
+    }
+}
@@ -1,67 +1,228 @@
-use std::collections::HashMap;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use crate::util;
+use super::{near_allocator::NearAllocation, thread_suspender};
+use crate::{
+    error::{Error, Result},
+    mid_hook::{self, MidHook, MidHookCallback},
+    util,
+    x86::{self, Instruction, Kind, Mode},
+};
 
 struct Patch {
     original_bytes: Box<[u8]>,
+    atomic: bool,
+    /// Stubs that the patched branch jumps through, freed once the original bytes are restored.
+    jump_stubs: Vec<JumpStub>,
 }
 
 impl Patch {
     fn original_bytes(&self) -> &[u8] {
         &self.original_bytes
     }
+
+    /// Restores the original bytes, using the same write mode as the patch.
+    unsafe fn restore(&self, patcher: &Patcher, address: usize) {
+        let ptr = util::make_ptr(address);
+        if self.atomic {
+            // The range was validated when the patch was applied.
+            let _ = patcher.safe_write_atomic(ptr, self.original_bytes());
+        } else {
+            patcher.safe_write(ptr, self.original_bytes());
+        }
+    }
+}
+
+/// A stub that a patched branch jumps through to reach a destination out of its range.
+struct JumpStub {
+    memory: NearAllocation,
+    destination: usize,
+}
+
+impl JumpStub {
+    fn new(near: usize, destination: usize) -> Result<JumpStub> {
+        Ok(JumpStub {
+            memory: NearAllocation::jump_stub(near, destination)?,
+            destination,
+        })
+    }
+
+    fn address(&self) -> usize {
+        self.memory.as_ptr() as usize
+    }
+
+    /// Moves suspended threads that are about to execute the stub to its destination, so that
+    /// it can be freed.
+    fn evacuate(&self) -> Result<()> {
+        let stub = self.address();
+        thread_suspender::relocate_suspended_instruction_pointers(
+            stub..stub + self.memory.size(),
+            |ip| (ip == stub).then_some(self.destination),
+        )
+    }
+}
+
+/// A mid hook installed by [`Patcher::mid_hook`], along with the stub that calls it.
+struct InstalledMidHook {
+    stub: NearAllocation,
+    stub_length: usize,
+    /// Pairs of overwritten instruction addresses and their copies in the stub.
+    instruction_map: Vec<(usize, usize)>,
+    // Declared after `stub`, so that the callback outlives the code that calls it.
+    _hook: MidHook,
+}
+
+impl InstalledMidHook {
+    /// Moves suspended threads that are inside the stub out of it, so that it can be freed:
+    /// threads about to execute a relocated instruction go back to the original.
+    fn evacuate(&self) -> Result<()> {
+        let stub = self.stub.as_ptr() as usize;
+        thread_suspender::relocate_suspended_instruction_pointers(
+            stub..stub + self.stub_length,
+            |ip| {
+                self.instruction_map
+                    .iter()
+                    .find(|(_, relocated)| *relocated == ip)
+                    .map(|(original, _)| *original)
+            },
+        )
+    }
 }
 
 pub struct Patcher {
-    patches: HashMap<usize, Patch>,
+    patches: BTreeMap<usize, Patch>,
+    mid_hooks: BTreeMap<usize, InstalledMidHook>,
+    /// Removed mid hooks that a suspended thread could not be moved out of.
+    retired_mid_hooks: Vec<InstalledMidHook>,
+    /// Jump stubs of removed patches that a suspended thread could not be moved out of.
+    retired_jump_stubs: Vec<JumpStub>,
 }
 
 #[allow(clippy::missing_safety_doc)]
 impl Patcher {
-    pub fn new() -> Patcher {
+    pub const fn new() -> Patcher {
         Patcher {
-            patches: HashMap::new(),
+            patches: BTreeMap::new(),
+            mid_hooks: BTreeMap::new(),
+            retired_mid_hooks: vec![],
+            retired_jump_stubs: vec![],
         }
     }
 
+    /// Writes `bytes` to `ptr`, temporarily making the memory writable, and flushes the
+    /// instruction cache for the written range.
+    ///
+    /// The bytes are copied without any synchronisation, so other threads must not be executing
+    /// or reading the range while it is written; suspend them with a
+    /// [`ThreadSuspender`](super::ThreadSuspender) or use [`Patcher::safe_write_atomic`].
     pub unsafe fn safe_write(&self, ptr: *mut u8, bytes: &[u8]) {
-        use windows::Win32::System::Memory::{
-            VirtualProtect, PAGE_EXECUTE_READWRITE, PAGE_PROTECTION_FLAGS,
-        };
-
-        let mut old: PAGE_PROTECTION_FLAGS = Default::default();
         let len = bytes.len();
+        with_writable(ptr, len, || {
+            std::slice::from_raw_parts_mut(ptr, len).copy_from_slice(bytes);
+        });
+    }
 
-        VirtualProtect(ptr as _, len, PAGE_EXECUTE_READWRITE, &mut old).unwrap();
-        std::slice::from_raw_parts_mut(ptr, len).copy_from_slice(bytes);
-        VirtualProtect(ptr as _, len, old, &mut old).unwrap();
+    /// Writes `bytes` to `ptr` with a single atomic operation, so that other threads observe
+    /// either all of the old bytes or all of the new ones. This makes it safe to patch code that
+    /// may be executing without suspending threads.
+    ///
+    /// The range must be at most 8 bytes long and must fit inside an aligned 8-byte word (or an
+    /// aligned 16-byte block on x64, where `cmpxchg16b` is used). The instruction cache is
+    /// flushed for the written range.
+    pub unsafe fn safe_write_atomic(&self, ptr: *mut u8, bytes: &[u8]) -> Result<()> {
+        let width =
+            atomic_write_width(ptr as usize, bytes.len()).ok_or(Error::AtomicWriteUnsupported {
+                address: ptr as usize,
+                length: bytes.len(),
+            })?;
+        with_writable(ptr, bytes.len(), || atomic_write(ptr, bytes, width));
+        Ok(())
     }
 
     /// Patches memory at the given address with the provided bytes.
     ///
     /// If a patch already exists at this address, the original bytes from the first patch
     /// are preserved and reused. This ensures that unpatching will always restore the
-    /// true original bytes, not bytes from a previous patch.
+    /// true original bytes, not bytes from a previous patch. If the new patch is longer than
+    /// the first one, the original bytes are extended to cover it.
     ///
     /// # Safety
     ///
     /// - `address` must be a valid memory address
     /// - The memory at `address` must be readable and writable
     /// - `bytes.len()` bytes must be safe to read/write at `address`
-    pub unsafe fn patch(&mut self, address: usize, bytes: &[u8]) {
+    ///
+    /// If threads are suspended by a [`ThreadSuspender`](super::ThreadSuspender), any of them
+    /// executing inside the patched range is first given the chance to leave it; an error is
+    /// returned if one does not.
+    pub unsafe fn patch(&mut self, address: usize, bytes: &[u8]) -> Result<()> {
+        thread_suspender::relocate_suspended_instruction_pointers(
+            address..address + bytes.len(),
+            |_| None,
+        )?;
+        self.record_patch(address, bytes.len(), false);
+        self.safe_write(util::make_ptr(address), bytes);
+        Ok(())
+    }
+
+    /// Patches memory at the given address with the provided bytes using a single atomic
+    /// write; see [`Patcher::safe_write_atomic`] for the requirements on the range.
+    ///
+    /// Unpatching restores the original bytes atomically as well, as long as they still fit
+    /// the requirements (i.e. the address was not previously patched with a longer patch).
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as [`Patcher::patch`] apply.
+    pub unsafe fn patch_atomic(&mut self, address: usize, bytes: &[u8]) -> Result<()> {
+        if atomic_write_width(address, bytes.len()).is_none() {
+            return Err(Error::AtomicWriteUnsupported {
+                address,
+                length: bytes.len(),
+            });
+        }
+        self.record_patch(address, bytes.len(), true);
+        self.safe_write_atomic(util::make_ptr(address), bytes)
+    }
+
+    unsafe fn record_patch(&mut self, address: usize, len: usize, atomic: bool) {
         let addr_ptr = util::make_ptr::<u8>(address);
 
-        // If a patch already exists, reuse its original_bytes
-        let original_bytes = if let Some(existing_patch) = self.patches.remove(&address) {
-            existing_patch.original_bytes
+        // If a patch already exists, reuse its original_bytes, extending them with the
+        // untouched bytes that follow if the new patch is longer
+        let mut jump_stubs = vec![];
+        let original_bytes: Box<[u8]> = if let Some(existing_patch) = self.patches.remove(&address)
+        {
+            // The previous patch's stubs are kept until the address is unpatched, as a thread
+            // may still be on its way through them.
+            jump_stubs = existing_patch.jump_stubs;
+            let mut original_bytes = existing_patch.original_bytes.into_vec();
+            if original_bytes.len() < len {
+                original_bytes.extend_from_slice(std::slice::from_raw_parts(
+                    addr_ptr.add(original_bytes.len()),
+                    len - original_bytes.len(),
+                ));
+            }
+            original_bytes.into()
         } else {
             // No existing patch, read the original bytes from memory
-            std::slice::from_raw_parts(addr_ptr, bytes.len()).into()
+            std::slice::from_raw_parts(addr_ptr, len).into()
         };
 
-        self.patches.insert(address, Patch { original_bytes });
-
-        self.safe_write(addr_ptr, bytes)
+        let atomic = atomic && atomic_write_width(address, original_bytes.len()).is_some();
+        self.patches.insert(
+            address,
+            Patch {
+                original_bytes,
+                atomic,
+                jump_stubs,
+            },
+        );
     }
 
     /// Removes a patch at the given address, restoring the original bytes.
@@ -71,43 +232,420 @@ impl Patcher {
     /// many times the address was patched, as the first patch's original bytes are
     /// always preserved.
     ///
+    /// If the patch is a [mid hook](Patcher::mid_hook), its stub and callback are freed as
+    /// well, once any threads suspended inside the stub have been moved out of it. So are the
+    /// jump stubs of a replaced branch destination.
+    ///
     /// # Safety
     ///
     /// - `address` must be a valid memory address
     /// - The memory at `address` must be readable and writable
     /// - The patch must have been created with the same `bytes.len()` as the original patch
     pub unsafe fn unpatch(&mut self, address: usize) -> Option<()> {
-        let original_bytes = self.patches.get(&address)?.original_bytes().to_owned();
-        self.safe_write(util::make_ptr(address), &original_bytes);
-        self.patches.remove(&address).map(|_| ())
+        let mut patch = self.patches.remove(&address)?;
+        patch.restore(self, address);
+        for stub in patch.jump_stubs.drain(..) {
+            if stub.evacuate().is_err() {
+                self.retired_jump_stubs.push(stub);
+            }
+        }
+        if let Some(hook) = self.mid_hooks.remove(&address) {
+            if hook.evacuate().is_err() {
+                self.retired_mid_hooks.push(hook);
+            }
+        }
+        Some(())
+    }
+
+    /// Patches the value at the given address with `value`, returning the original value.
+    ///
+    /// The value is written in its in-memory (little-endian) representation. As with
+    /// [`Patcher::patch`], repeated patches of the same address keep the true original value,
+    /// which can be retrieved later through [`Patcher::original`].
+    ///
+    /// # Safety
+    ///
+    /// - `address` must be valid for reads and writes of a `T`
+    /// - `T` must not contain padding bytes
+    pub unsafe fn patch_value<T: Copy>(&mut self, address: usize, value: T) -> Result<T> {
+        self.patch(address, util::bytes_of(&value))?;
+        Ok(self
+            .original(address)
+            .expect("patch was just applied with the size of the value"))
+    }
+
+    /// Returns the original bytes at the given address, if it has been patched.
+    pub fn original_bytes(&self, address: usize) -> Option<&[u8]> {
+        self.patches
+            .get(&address)
+            .map(|patch| patch.original_bytes())
+    }
+
+    /// Returns the original value at the given address, if it has been patched with at least
+    /// `size_of::<T>()` bytes.
+    pub fn original<T: Copy>(&self, address: usize) -> Option<T> {
+        let original_bytes = self.patches.get(&address)?.original_bytes();
+        if original_bytes.len() < std::mem::size_of::<T>() {
+            return None;
+        }
+        Some(unsafe { std::ptr::read_unaligned(original_bytes.as_ptr() as *const T) })
     }
 
     /// Replace a 5-byte call (0xE8 CALL rel16/32) at `src` with a call to our destination `dst`.
     ///
-    /// On 64-bit platforms, the destination must be within 32-bit range.
-    pub unsafe fn replace_call_destination(&mut self, src: usize, dst: usize) -> usize {
-        // First, we determine what the original destination of the call was.
-        let orig_call_target: *mut i32 = util::make_ptr_with_offset(src, 1);
-        let orig_call_dest = (*orig_call_target as isize) + (src as isize) + 5;
-
-        // Next, we generate a new call to our destination.
-        let new_call_target = dst - src - 5;
-        let new_call_target: i32 = new_call_target.try_into().unwrap_or_else(|_| panic!(
-            "call target out of range {src:x?} {dst:x?} {orig_call_dest:x?} {new_call_target:x?} (must be within 32-bit range)"
-        ));
-        let new_bytes: [u8; 5] = {
-            let mut bytes = [0; 5];
-            bytes[0] = 0xE8;
-            bytes[1..].copy_from_slice(&new_call_target.to_le_bytes());
-            bytes
+    /// If `dst` is not within rel32 range of `src` (which can happen on 64-bit platforms), the
+    /// call is routed through a jump stub allocated near `src`. The stub is freed when `src` is
+    /// unpatched.
+    ///
+    /// Returns the original destination of the call.
+    pub unsafe fn replace_call_destination(&mut self, src: usize, dst: usize) -> Result<usize> {
+        let instruction = decode_at(src)?;
+        if instruction.kind != Kind::Call {
+            return Err(Error::UnexpectedInstruction {
+                address: src,
+                expected: "call rel32",
+            });
+        }
+        self.replace_branch_target(src, &instruction, dst)
+    }
+
+    /// Replace a 5-byte jump (0xE9 JMP rel32) at `src` with a jump to our destination `dst`.
+    ///
+    /// Like [`Patcher::replace_call_destination`], this goes through a jump stub if `dst` is out
+    /// of range. Returns the original destination of the jump.
+    pub unsafe fn replace_jump_destination(&mut self, src: usize, dst: usize) -> Result<usize> {
+        let instruction = decode_at(src)?;
+        if instruction.kind != Kind::Jump {
+            return Err(Error::UnexpectedInstruction {
+                address: src,
+                expected: "jmp rel32",
+            });
+        }
+        self.replace_branch_target(src, &instruction, dst)
+    }
+
+    /// Replace the destination of a conditional jump (0x7x Jcc rel8 or 0x0F 0x8x Jcc rel32) at
+    /// `src` with our destination `dst`.
+    ///
+    /// A short jump whose new destination is out of rel8 range is promoted to the 6-byte near
+    /// form, but only if `max_length` bytes starting at `src` may be overwritten; any bytes
+    /// beyond the new instruction are filled with NOPs. Execution continues after those bytes
+    /// when the condition is not met, so this is intended for jumps followed by padding or by
+    /// instructions that the caller is replacing anyway.
+    ///
+    /// Returns the original destination of the jump.
+    pub unsafe fn replace_conditional_jump_destination(
+        &mut self,
+        src: usize,
+        dst: usize,
+        max_length: usize,
+    ) -> Result<usize> {
+        let instruction = decode_at(src)?;
+        let Kind::ConditionalJump { condition } = instruction.kind else {
+            return Err(Error::UnexpectedInstruction {
+                address: src,
+                expected: "jcc",
+            });
         };
+        if instruction.length != 2 {
+            return self.replace_branch_target(src, &instruction, dst);
+        }
 
-        // Finally, we patch the existing call and return the original destination.
-        self.patch(src, &new_bytes);
-        orig_call_dest as usize
+        let original = instruction.target(src).unwrap_or_default();
+        if let Ok(displacement) = i8::try_from((dst as isize).wrapping_sub(src as isize + 2)) {
+            self.patch(src, &[0x70 | condition, displacement as u8])?;
+            return Ok(original);
+        }
+        if max_length < 6 {
+            return Err(Error::TargetOutOfRange {
+                address: src,
+                target: dst,
+            });
+        }
+
+        let (displacement, stub) = branch_displacement(src, src + 6, dst)?;
+        let mut bytes = vec![0x90; max_length];
+        bytes[..2].copy_from_slice(&[0x0F, 0x80 | condition]);
+        bytes[2..6].copy_from_slice(&displacement.to_le_bytes());
+        self.patch(src, &bytes)?;
+        self.keep_jump_stub(src, stub);
+        Ok(original)
+    }
+
+    /// Replace the target of the RIP-relative memory operand of the instruction at `address`
+    /// (e.g. `lea rax, [rip+disp32]` or `mov eax, [rip+disp32]`) with `target`.
+    ///
+    /// Unlike branches, data references cannot be routed through a stub, so `target` must be
+    /// within rel32 range of the instruction. Returns the original target.
+    pub unsafe fn replace_rip_relative_target(
+        &mut self,
+        address: usize,
+        target: usize,
+    ) -> Result<usize> {
+        let instruction = decode_at(address)?;
+        if !instruction.is_rip_relative() {
+            return Err(Error::UnexpectedInstruction {
+                address,
+                expected: "RIP-relative memory operand",
+            });
+        }
+
+        let displacement = rel32(address + instruction.length, target)
+            .ok_or(Error::TargetOutOfRange { address, target })?;
+        self.replace_displacement(address, &instruction, displacement)
+    }
+
+    /// Hooks the instruction at `address`, calling `callback` with the registers every time it
+    /// is about to execute. The callback may modify the registers before execution resumes.
+    ///
+    /// The instructions overwritten by the jump to the hook are relocated into a stub next to
+    /// `address`. The hook is removed with [`Patcher::unpatch`], which frees the stub and
+    /// callback; hooking an address again replaces its previous hook.
+    ///
+    /// # Safety
+    ///
+    /// - `address` must be the start of an instruction, and the instructions overwritten by the
+    ///   5-byte jump must not be branch targets
+    /// - No thread may be executing the overwritten instructions while the hook is installed,
+    ///   nor the callback while it is removed, unless suspended by a
+    ///   [`ThreadSuspender`](super::ThreadSuspender)
+    pub unsafe fn mid_hook(
+        &mut self,
+        address: usize,
+        callback: Arc<MidHookCallback>,
+    ) -> Result<()> {
+        if self.mid_hooks.contains_key(&address) {
+            self.unpatch(address);
+        }
+
+        let hook = MidHook::new(callback);
+        let memory = NearAllocation::new(address, mid_hook::MAX_STUB_LENGTH)?;
+        let stub = memory.as_ptr();
+        let code = readable_code(address, mid_hook::MAX_OVERWRITTEN_LENGTH);
+        let generated = hook.generate(address, code, stub as usize)?;
+
+        std::slice::from_raw_parts_mut(stub, generated.stub.len()).copy_from_slice(&generated.stub);
+        // Threads about to execute the overwritten instructions continue in the stub instead.
+        thread_suspender::relocate_suspended_instruction_pointers(
+            address..address + generated.entry.len(),
+            |ip| {
+                generated
+                    .instruction_map
+                    .iter()
+                    .find(|(original, _)| *original == ip)
+                    .map(|(_, relocated)| *relocated)
+            },
+        )?;
+        self.patch(address, &generated.entry)?;
+        self.mid_hooks.insert(
+            address,
+            InstalledMidHook {
+                stub: memory,
+                stub_length: generated.stub.len(),
+                instruction_map: generated.instruction_map,
+                _hook: hook,
+            },
+        );
+        Ok(())
+    }
+
+    /// Rewrites the rel32 of a near branch, going through a jump stub if required.
+    unsafe fn replace_branch_target(
+        &mut self,
+        src: usize,
+        instruction: &Instruction,
+        dst: usize,
+    ) -> Result<usize> {
+        let (displacement, stub) = branch_displacement(src, src + instruction.length, dst)?;
+        let original = self.replace_displacement(src, instruction, displacement)?;
+        self.keep_jump_stub(src, stub);
+        Ok(original)
+    }
+
+    /// Stores the jump stub that the branch patched at `src` goes through, if any, with its
+    /// patch.
+    fn keep_jump_stub(&mut self, src: usize, stub: Option<JumpStub>) {
+        if let Some(stub) = stub {
+            self.patches
+                .get_mut(&src)
+                .expect("branch was just patched")
+                .jump_stubs
+                .push(stub);
+        }
+    }
+
+    /// Patches the 32-bit relative displacement of `instruction` and returns its original target.
+    unsafe fn replace_displacement(
+        &mut self,
+        address: usize,
+        instruction: &Instruction,
+        displacement: i32,
+    ) -> Result<usize> {
+        let relative = instruction
+            .relative
+            .filter(|relative| relative.size == 4)
+            .ok_or(Error::UnexpectedInstruction {
+                address,
+                expected: "rel32 displacement",
+            })?;
+        let original = instruction.target(address).unwrap_or_default();
+
+        let mut bytes =
+            std::slice::from_raw_parts(address as *const u8, instruction.length).to_vec();
+        bytes[relative.offset..relative.offset + 4].copy_from_slice(&displacement.to_le_bytes());
+        self.patch(address, &bytes)?;
+
+        Ok(original)
+    }
+}
+
+/// Makes `len` bytes at `ptr` writable for the duration of `write`, then restores the original
+/// protection and flushes the instruction cache for the range.
+unsafe fn with_writable(ptr: *mut u8, len: usize, write: impl FnOnce()) {
+    use windows::Win32::System::{
+        Diagnostics::Debug::FlushInstructionCache,
+        Memory::{VirtualProtect, PAGE_EXECUTE_READWRITE, PAGE_PROTECTION_FLAGS},
+        Threading::GetCurrentProcess,
+    };
+
+    let mut old: PAGE_PROTECTION_FLAGS = Default::default();
+
+    VirtualProtect(ptr as _, len, PAGE_EXECUTE_READWRITE, &mut old).unwrap();
+    write();
+    VirtualProtect(ptr as _, len, old, &mut old).unwrap();
+    FlushInstructionCache(GetCurrentProcess(), Some(ptr as _), len).unwrap();
+}
+
+/// Returns the width of the aligned block that `len` bytes at `address` can be atomically
+/// written through, if any.
+fn atomic_write_width(address: usize, len: usize) -> Option<usize> {
+    let fits = |width: usize| address % width + len <= width;
+    if len == 0 || len > 8 {
+        None
+    } else if fits(8) {
+        Some(8)
+    } else if cfg!(target_arch = "x86_64") && fits(16) {
+        Some(16)
+    } else {
+        None
+    }
+}
+
+/// Merges `bytes` into the aligned block of `width` bytes containing `ptr` with a
+/// compare-and-swap loop.
+unsafe fn atomic_write(ptr: *mut u8, bytes: &[u8], width: usize) {
+    let address = ptr as usize;
+    let block = address - address % width;
+    let shift = (address - block) * 8;
+
+    let mut mask = 0u128;
+    let mut value = 0u128;
+    for (index, byte) in bytes.iter().enumerate() {
+        mask |= 0xFF << (shift + index * 8);
+        value |= (*byte as u128) << (shift + index * 8);
+    }
+    let merge = |current: u128| (current & !mask) | value;
+
+    if width == 8 {
+        let atomic = &*(block as *const AtomicU64);
+        let _ = atomic.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+            Some(merge(current as u128) as u64)
+        });
+    } else {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let block = block as *mut u128;
+            let mut current = std::ptr::read_volatile(block);
+            loop {
+                let previous = compare_exchange_16(block, current, merge(current));
+                if previous == current {
+                    break;
+                }
+                current = previous;
+            }
+        }
     }
 }
 
+/// Atomically replaces the 16 bytes at `ptr` with `new` if they are equal to `current`,
+/// returning the previous value.
+#[cfg(target_arch = "x86_64")]
+unsafe fn compare_exchange_16(ptr: *mut u128, current: u128, new: u128) -> u128 {
+    let (mut low, mut high) = (current as u64, (current >> 64) as u64);
+    // `rbx` is reserved by LLVM, so the low half of `new` is swapped in and out of it.
+    std::arch::asm!(
+        "xchg {new_low}, rbx",
+        "lock cmpxchg16b xmmword ptr [{ptr}]",
+        "mov rbx, {new_low}",
+        ptr = in(reg) ptr,
+        new_low = inout(reg) new as u64 => _,
+        in("rcx") (new >> 64) as u64,
+        inout("rax") low,
+        inout("rdx") high,
+        options(nostack),
+    );
+    ((high as u128) << 64) | low as u128
+}
+
+/// Decodes the instruction at `address`.
+unsafe fn decode_at(address: usize) -> Result<Instruction> {
+    let bytes = readable_code(address, x86::MAX_INSTRUCTION_LENGTH);
+    x86::decode(bytes, Mode::NATIVE).ok_or(Error::InstructionDecodeFailed { address })
+}
+
+/// Returns up to `max_length` bytes of code at `address`, stopping early at the end of the
+/// committed, readable memory, so that decoding near the end of a mapping doesn't read past
+/// it.
+pub(super) unsafe fn readable_code<'a>(address: usize, max_length: usize) -> &'a [u8] {
+    use windows::Win32::System::Memory::{
+        VirtualQuery, MEMORY_BASIC_INFORMATION, MEM_COMMIT, PAGE_GUARD, PAGE_NOACCESS,
+    };
+
+    let end = address.saturating_add(max_length);
+    let mut readable_end = address;
+    while readable_end < end {
+        let mut info = MEMORY_BASIC_INFORMATION::default();
+        let queried = VirtualQuery(
+            Some(readable_end as _),
+            &mut info,
+            std::mem::size_of::<MEMORY_BASIC_INFORMATION>(),
+        );
+        if queried == 0
+            || info.State != MEM_COMMIT
+            || (info.Protect & (PAGE_NOACCESS | PAGE_GUARD)).0 != 0
+        {
+            break;
+        }
+        readable_end = info.BaseAddress as usize + info.RegionSize;
+    }
+    std::slice::from_raw_parts(address as *const u8, readable_end.min(end) - address)
+}
+
+/// Computes the rel32 from `next_instruction` to `dst`, allocating a jump stub near `src` if
+/// `dst` is out of range.
+fn branch_displacement(
+    src: usize,
+    next_instruction: usize,
+    dst: usize,
+) -> Result<(i32, Option<JumpStub>)> {
+    if let Some(displacement) = rel32(next_instruction, dst) {
+        return Ok((displacement, None));
+    }
+    let stub = JumpStub::new(src, dst)?;
+    let displacement = rel32(next_instruction, stub.address())
+        .ok_or(Error::NearAllocationFailed { address: src })?;
+    Ok((displacement, Some(stub)))
+}
+
+/// Computes the rel32 displacement from the end of an instruction at `next_instruction` to
+/// `destination`, if it is in range.
+fn rel32(next_instruction: usize, destination: usize) -> Option<i32> {
+    (destination as isize)
+        .wrapping_sub(next_instruction as isize)
+        .try_into()
+        .ok()
+}
+
 impl Default for Patcher {
     fn default() -> Self {
         Self::new()
@@ -118,8 +656,36 @@ impl Drop for Patcher {
     fn drop(&mut self) {
         for (address, patch) in self.patches.iter() {
             unsafe {
-                self.safe_write(util::make_ptr(*address), patch.original_bytes());
+                patch.restore(self, *address);
             }
         }
     }
 }
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::test_util::ExecutableMemory;
+
+    #[test]
+    fn far_branches_go_through_a_stub_owned_by_the_patch() {
+        let memory = ExecutableMemory::new(4096);
+        // call +0x10
+        let src = memory.write(0, &[0xE8, 0x10, 0x00, 0x00, 0x00]);
+        let far = src.wrapping_add(0x1_0000_0000);
+
+        let mut patcher = Patcher::new();
+        let original = unsafe { patcher.replace_call_destination(src, far) }.unwrap();
+        assert_eq!(original, src + 0x15);
+
+        let stub = unsafe { decode_at(src) }.unwrap().target(src).unwrap();
+        let mut jump = vec![0xFF, 0x25, 0x00, 0x00, 0x00, 0x00];
+        jump.extend((far as u64).to_le_bytes());
+        assert_eq!(unsafe { readable_code(stub, jump.len()) }, jump);
+        assert_eq!(patcher.patches[&src].jump_stubs.len(), 1);
+
+        unsafe { patcher.unpatch(src) }.unwrap();
+        assert_eq!(memory.bytes(0)[..5], [0xE8, 0x10, 0x00, 0x00, 0x00]);
+        assert!(patcher.retired_jump_stubs.is_empty());
+    }
+}
@@ -1,19 +1,28 @@
-use std::mem;
+use std::{mem, ops::Range, sync::Mutex};
 
 use windows::Win32::{
     Foundation::{CloseHandle, HANDLE},
     System::{
-        Diagnostics::ToolHelp::{
-            CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD,
+        Diagnostics::{
+            Debug::{GetThreadContext, SetThreadContext, CONTEXT, CONTEXT_FLAGS},
+            ToolHelp::{CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD},
         },
         Threading::{
-            GetCurrentProcessId, GetCurrentThreadId, OpenThread, ResumeThread, SuspendThread,
-            THREAD_ALL_ACCESS,
+            GetCurrentProcessId, GetCurrentThreadId, OpenThread, ResumeThread, Sleep,
+            SuspendThread, THREAD_ALL_ACCESS,
         },
     },
 };
 
-use crate::error::{Result, UserCallbackResult, WindowsError};
+use crate::error::{Error, Result, UserCallbackResult, WindowsError};
+
+/// The threads suspended by every live [`ThreadSuspender`], so that code patches can make sure
+/// none of them is executing the bytes being overwritten. Handles are stored as integers, as
+/// `HANDLE` is not `Send`.
+static SUSPENDED_THREADS: Mutex<Vec<usize>> = Mutex::new(vec![]);
+
+/// How many times a thread is briefly resumed to let it leave a range before giving up.
+const MAX_ATTEMPTS: u32 = 100;
 
 pub struct ThreadSuspender {
     threads: Vec<HANDLE>,
@@ -62,6 +71,10 @@ impl ThreadSuspender {
             .collect::<Result<Vec<_>>>()?;
 
         Self::suspend(&threads);
+        SUSPENDED_THREADS
+            .lock()
+            .unwrap()
+            .extend(threads.iter().map(|thread| thread.0 as usize));
         Ok(Self { threads })
     }
     fn suspend(threads: &[HANDLE]) {
@@ -89,10 +102,118 @@ impl ThreadSuspender {
         let _suspender = ThreadSuspender::new()?;
         f()
     }
+    /// Makes sure that none of the suspended threads is executing inside `range`, which is
+    /// about to be overwritten.
+    ///
+    /// A thread inside the range is moved to `relocate(ip)` if that returns an address (e.g.
+    /// the matching instruction in a trampoline). Otherwise it is briefly resumed until it
+    /// leaves the range; an error is returned if it does not leave it in time.
+    pub fn relocate_instruction_pointers(
+        &self,
+        range: Range<usize>,
+        relocate: impl Fn(usize) -> Option<usize>,
+    ) -> Result<()> {
+        relocate_instruction_pointers(&self.threads, range, relocate)
+    }
 }
 impl Drop for ThreadSuspender {
     fn drop(&mut self) {
+        SUSPENDED_THREADS
+            .lock()
+            .unwrap()
+            .retain(|thread| !self.threads.iter().any(|t| t.0 as usize == *thread));
         Self::resume(&self.threads);
         Self::close(&self.threads);
     }
 }
+
+/// Applies [`ThreadSuspender::relocate_instruction_pointers`] to the threads of every live
+/// `ThreadSuspender`. Does nothing if no threads are suspended.
+pub(crate) fn relocate_suspended_instruction_pointers(
+    range: Range<usize>,
+    relocate: impl Fn(usize) -> Option<usize>,
+) -> Result<()> {
+    // Copied so that the lock is released before any thread is briefly resumed, as that thread
+    // may itself create or drop a `ThreadSuspender`.
+    let threads: Vec<HANDLE> = SUSPENDED_THREADS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|thread| HANDLE(*thread as _))
+        .collect();
+    relocate_instruction_pointers(&threads, range, relocate)
+}
+
+fn relocate_instruction_pointers(
+    threads: &[HANDLE],
+    range: Range<usize>,
+    relocate: impl Fn(usize) -> Option<usize>,
+) -> Result<()> {
+    for thread in threads {
+        let mut attempts = 0;
+        loop {
+            let mut context = ThreadContext::get(*thread)?;
+            let ip = context.instruction_pointer();
+            if !range.contains(&ip) {
+                break;
+            }
+            if let Some(new_ip) = relocate(ip) {
+                context.set_instruction_pointer(new_ip);
+                context.set(*thread)?;
+                break;
+            }
+
+            attempts += 1;
+            if attempts == MAX_ATTEMPTS {
+                return Err(Error::ThreadInPatchedRange { address: ip });
+            }
+            unsafe {
+                ResumeThread(*thread);
+                Sleep(1);
+                SuspendThread(*thread);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The control registers of a suspended thread. `CONTEXT` must be 16-byte aligned on x64.
+#[repr(C, align(16))]
+struct ThreadContext(CONTEXT);
+
+impl ThreadContext {
+    #[cfg(target_arch = "x86_64")]
+    const FLAGS: CONTEXT_FLAGS = windows::Win32::System::Diagnostics::Debug::CONTEXT_CONTROL_AMD64;
+    #[cfg(target_arch = "x86")]
+    const FLAGS: CONTEXT_FLAGS = windows::Win32::System::Diagnostics::Debug::CONTEXT_CONTROL_X86;
+
+    fn get(thread: HANDLE) -> Result<Self> {
+        let mut context = ThreadContext(CONTEXT {
+            ContextFlags: Self::FLAGS,
+            ..Default::default()
+        });
+        unsafe { GetThreadContext(thread, &mut context.0) }
+            .map_err(|e| WindowsError::ThreadContextFailed { source: e })?;
+        Ok(context)
+    }
+    fn set(&self, thread: HANDLE) -> Result<()> {
+        unsafe { SetThreadContext(thread, &self.0) }
+            .map_err(|e| WindowsError::ThreadContextFailed { source: e }.into())
+    }
+    #[cfg(target_arch = "x86_64")]
+    fn instruction_pointer(&self) -> usize {
+        self.0.Rip as usize
+    }
+    #[cfg(target_arch = "x86_64")]
+    fn set_instruction_pointer(&mut self, ip: usize) {
+        self.0.Rip = ip as u64;
+    }
+    #[cfg(target_arch = "x86")]
+    fn instruction_pointer(&self) -> usize {
+        self.0.Eip as usize
+    }
+    #[cfg(target_arch = "x86")]
+    fn set_instruction_pointer(&mut self, ip: usize) {
+        self.0.Eip = ip as u32;
+    }
+}
@@ -0,0 +1,163 @@
+use std::{ffi::c_void, marker::PhantomData, mem, sync::Mutex};
+
+use super::{detour_binder::DetourBinder, patcher::Patcher};
+use crate::{error::UserCallbackResult, function::Function};
+
+/// Returns the address of the function in slot `index` of `object`'s vtable.
+///
+/// # Safety
+/// `object` must point to an object whose first field is a pointer to a vtable with more than
+/// `index` slots.
+pub unsafe fn virtual_function(object: *const c_void, index: usize) -> usize {
+    let vtable = *(object as *const *const usize);
+    *vtable.add(index)
+}
+
+/// A hook that replaces a slot in a vtable with a detour, affecting every object that uses
+/// the vtable. The slot is written atomically through the hook's own [`Patcher`], and
+/// restored when the hook is disabled or dropped. Unlike a detour, no trampoline is needed:
+/// the original function can be called directly.
+pub struct VtableHook<F: Function> {
+    slot: usize,
+    original: usize,
+    detour: usize,
+    patcher: Mutex<Patcher>,
+    _function: PhantomData<F>,
+}
+
+impl<F: Function> VtableHook<F> {
+    /// Creates a hook for slot `index` of the vtable at `vtable`. The hook starts disabled.
+    ///
+    /// # Safety
+    /// `vtable` must point to a vtable with more than `index` slots, and the function in the
+    /// slot must have the type `F`.
+    pub unsafe fn new(vtable: usize, index: usize, detour: F) -> Self {
+        let slot = vtable + index * mem::size_of::<usize>();
+        VtableHook {
+            slot,
+            original: *(slot as *const usize),
+            detour: detour.to_ptr() as usize,
+            patcher: Mutex::new(Patcher::new()),
+            _function: PhantomData,
+        }
+    }
+
+    /// Creates a hook for slot `index` of the vtable used by `object`. Every object that
+    /// shares the vtable is affected; see [`ShadowVtableHook`] to hook a single object.
+    ///
+    /// # Safety
+    /// `object` must point to an object whose first field is a pointer to a vtable with more
+    /// than `index` slots, and the function in the slot must have the type `F`.
+    pub unsafe fn for_object(object: *const c_void, index: usize, detour: F) -> Self {
+        Self::new(*(object as *const usize), index, detour)
+    }
+
+    /// The function that was in the slot when the hook was created.
+    pub fn original(&self) -> F {
+        unsafe { F::from_ptr(self.original as *const ()) }
+    }
+
+    /// The address of the slot in the vtable.
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.patcher
+            .lock()
+            .unwrap()
+            .original_bytes(self.slot)
+            .is_some()
+    }
+}
+
+impl<F: Function> DetourBinder for VtableHook<F> {
+    fn enable(&self) -> UserCallbackResult<()> {
+        let mut patcher = self.patcher.lock().unwrap();
+        if patcher.original_bytes(self.slot).is_none() {
+            unsafe { patcher.patch_atomic(self.slot, &self.detour.to_ne_bytes())? };
+        }
+        Ok(())
+    }
+    fn disable(&self) -> UserCallbackResult<()> {
+        unsafe { self.patcher.lock().unwrap().unpatch(self.slot) };
+        Ok(())
+    }
+    fn address(&self) -> Option<usize> {
+        Some(self.original)
+    }
+}
+
+/// A hook that gives a single object a copy of its vtable with one slot replaced, leaving
+/// other objects of the same class untouched. The object's vtable pointer is swapped
+/// atomically through the hook's own [`Patcher`].
+pub struct ShadowVtableHook<F: Function> {
+    object: usize,
+    original: usize,
+    // Declared before `shadow`, so that the object is pointed back at its own vtable before
+    // the copy is freed.
+    patcher: Mutex<Patcher>,
+    /// The copied vtable, preceded by the slot before it (which holds RTTI on MSVC).
+    shadow: Box<[usize]>,
+    _function: PhantomData<F>,
+}
+
+impl<F: Function> ShadowVtableHook<F> {
+    /// Creates a hook for slot `index` of `object`'s vtable, which has `slot_count` slots. The
+    /// hook starts disabled.
+    ///
+    /// # Safety
+    /// `object` must point to an object whose first field is a pointer to a vtable with at
+    /// least `slot_count` slots (and, like any vtable, a slot before them), and the function in
+    /// the slot must have the type `F`. The object must outlive the hook, or the hook must be
+    /// disabled and forgotten before the object is freed.
+    pub unsafe fn new(object: *mut c_void, slot_count: usize, index: usize, detour: F) -> Self {
+        assert!(index < slot_count, "slot {} is out of range", index);
+        let vtable = *(object as *const *const usize);
+        let mut shadow: Box<[usize]> =
+            std::slice::from_raw_parts(vtable.sub(1), slot_count + 1).into();
+        let original = mem::replace(&mut shadow[index + 1], detour.to_ptr() as usize);
+        ShadowVtableHook {
+            object: object as usize,
+            original,
+            patcher: Mutex::new(Patcher::new()),
+            shadow,
+            _function: PhantomData,
+        }
+    }
+
+    /// The function that was in the slot when the hook was created.
+    pub fn original(&self) -> F {
+        unsafe { F::from_ptr(self.original as *const ()) }
+    }
+
+    /// The address of the copied vtable.
+    pub fn shadow_vtable(&self) -> usize {
+        self.shadow.as_ptr() as usize + mem::size_of::<usize>()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.patcher
+            .lock()
+            .unwrap()
+            .original_bytes(self.object)
+            .is_some()
+    }
+}
+
+impl<F: Function> DetourBinder for ShadowVtableHook<F> {
+    fn enable(&self) -> UserCallbackResult<()> {
+        let mut patcher = self.patcher.lock().unwrap();
+        if patcher.original_bytes(self.object).is_none() {
+            unsafe { patcher.patch_atomic(self.object, &self.shadow_vtable().to_ne_bytes())? };
+        }
+        Ok(())
+    }
+    fn disable(&self) -> UserCallbackResult<()> {
+        unsafe { self.patcher.lock().unwrap().unpatch(self.object) };
+        Ok(())
+    }
+    fn address(&self) -> Option<usize> {
+        Some(self.original)
+    }
+}
@@ -0,0 +1,654 @@
+/// The longest an x86 instruction can be, including prefixes.
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// The processor mode to decode instructions for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    X86,
+    X64,
+}
+
+impl Mode {
+    /// The mode of the current process.
+    #[cfg(target_pointer_width = "64")]
+    pub const NATIVE: Mode = Mode::X64;
+    /// The mode of the current process.
+    #[cfg(target_pointer_width = "32")]
+    pub const NATIVE: Mode = Mode::X86;
+}
+
+/// The control-flow category of a decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// `call rel32`
+    Call,
+    /// `jmp rel8` or `jmp rel32`
+    Jump,
+    /// `jcc rel8` or `jcc rel32`, with the condition code in the low nibble of the opcode
+    ConditionalJump { condition: u8 },
+    /// `loop`, `loope`, `loopne`, `jcxz` and friends, which only have a rel8 form
+    Loop,
+    /// `call r/m`
+    IndirectCall,
+    /// `jmp r/m`
+    IndirectJump,
+    /// `ret` or `retf`, with or without an immediate
+    Return,
+    /// Anything else
+    Other,
+}
+
+/// A displacement inside an instruction that is relative to the start of the next instruction.
+///
+/// For branches this is the branch immediate; for anything else it is a RIP-relative memory
+/// operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relative {
+    /// The offset of the displacement within the instruction.
+    pub offset: usize,
+    /// The size of the displacement in bytes (1, 2 or 4).
+    pub size: usize,
+    /// The sign-extended value of the displacement.
+    pub displacement: isize,
+}
+
+/// A decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub length: usize,
+    pub kind: Kind,
+    pub relative: Option<Relative>,
+}
+
+impl Instruction {
+    /// Whether this is a direct branch whose target is encoded relative to the next instruction.
+    pub fn is_relative_branch(&self) -> bool {
+        matches!(
+            self.kind,
+            Kind::Call | Kind::Jump | Kind::ConditionalJump { .. } | Kind::Loop
+        )
+    }
+
+    /// Whether this instruction has a RIP-relative memory operand.
+    pub fn is_rip_relative(&self) -> bool {
+        self.relative.is_some() && !self.is_relative_branch()
+    }
+
+    /// The absolute address referenced by the relative displacement, if the instruction is
+    /// located at `address`.
+    pub fn target(&self, address: usize) -> Option<usize> {
+        let relative = self.relative?;
+        Some(
+            address
+                .wrapping_add(self.length)
+                .wrapping_add_signed(relative.displacement),
+        )
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`.
+///
+/// Returns `None` if the bytes do not begin with a complete instruction that is valid in `mode`.
+/// Only the length and relative operands are decoded; this is not a disassembler.
+pub fn decode(bytes: &[u8], mode: Mode) -> Option<Instruction> {
+    Decoder {
+        bytes: &bytes[..bytes.len().min(MAX_INSTRUCTION_LENGTH)],
+        position: 0,
+        mode,
+        operand_size_override: false,
+        address_size_override: false,
+        rex_w: false,
+        relative: None,
+    }
+    .decode()
+}
+
+#[derive(Clone, Copy)]
+enum Immediate {
+    None,
+    Byte,
+    Word,
+    /// 16 or 32 bits depending on the operand size.
+    Z,
+    /// 16, 32 or 64 bits depending on the operand size (`mov r, imm`).
+    V,
+    /// The size of an address (`mov al, moffs`).
+    Address,
+    /// A far pointer (`call ptr16:32`).
+    Far,
+    /// `enter imm16, imm8`
+    Enter,
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    mode: Mode,
+    operand_size_override: bool,
+    address_size_override: bool,
+    rex_w: bool,
+    relative: Option<Relative>,
+}
+
+impl Decoder<'_> {
+    fn decode(mut self) -> Option<Instruction> {
+        loop {
+            match self.peek()? {
+                0xF0 | 0xF2 | 0xF3 | 0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65 => {}
+                0x66 => self.operand_size_override = true,
+                0x67 => self.address_size_override = true,
+                _ => break,
+            }
+            self.position += 1;
+        }
+        // Redundant REX prefixes are allowed; only the last one takes effect.
+        while self.mode == Mode::X64 && matches!(self.peek()?, 0x40..=0x4F) {
+            self.rex_w = self.next()? & 0x08 != 0;
+        }
+
+        let opcode = self.next()?;
+        let kind = match opcode {
+            0x0F => self.two_byte()?,
+            0xC4 | 0xC5 | 0x62 if self.is_vex_or_evex()? => self.vex_or_evex(opcode)?,
+            _ => self.one_byte(opcode)?,
+        };
+
+        Some(Instruction {
+            length: self.position,
+            kind,
+            relative: self.relative,
+        })
+    }
+
+    fn one_byte(&mut self, opcode: u8) -> Option<Kind> {
+        let x64 = self.mode == Mode::X64;
+        let invalid_in_x64 = matches!(
+            opcode,
+            0x06 | 0x07
+                | 0x0E
+                | 0x16
+                | 0x17
+                | 0x1E
+                | 0x1F
+                | 0x27
+                | 0x2F
+                | 0x37
+                | 0x3F
+                | 0x60
+                | 0x61
+                | 0x82
+                | 0x9A
+                | 0xD4
+                | 0xD5
+                | 0xD6
+                | 0xEA
+        );
+        if x64 && invalid_in_x64 {
+            return None;
+        }
+
+        let mut kind = Kind::Other;
+        let (has_modrm, immediate) = match opcode {
+            0x00..=0x3F => match opcode & 0x07 {
+                0..=3 => (true, Immediate::None),
+                4 => (false, Immediate::Byte),
+                5 => (false, Immediate::Z),
+                _ => (false, Immediate::None),
+            },
+            0x40..=0x61 => (false, Immediate::None),
+            0x62 | 0x63 => (true, Immediate::None),
+            0x68 => (false, Immediate::Z),
+            0x69 => (true, Immediate::Z),
+            0x6A => (false, Immediate::Byte),
+            0x6B => (true, Immediate::Byte),
+            0x6C..=0x6F => (false, Immediate::None),
+            0x70..=0x7F => {
+                return self.relative_branch(
+                    Kind::ConditionalJump {
+                        condition: opcode & 0x0F,
+                    },
+                    1,
+                );
+            }
+            0x80 | 0x82 | 0x83 => (true, Immediate::Byte),
+            0x81 => (true, Immediate::Z),
+            0x84..=0x8F => (true, Immediate::None),
+            0x90..=0x99 | 0x9B..=0x9F => (false, Immediate::None),
+            0x9A => (false, Immediate::Far),
+            0xA0..=0xA3 => (false, Immediate::Address),
+            0xA4..=0xA7 | 0xAA..=0xAF => (false, Immediate::None),
+            0xA8 => (false, Immediate::Byte),
+            0xA9 => (false, Immediate::Z),
+            0xB0..=0xB7 => (false, Immediate::Byte),
+            0xB8..=0xBF => (false, Immediate::V),
+            0xC0 | 0xC1 | 0xC6 => (true, Immediate::Byte),
+            0xC2 | 0xCA => {
+                kind = Kind::Return;
+                (false, Immediate::Word)
+            }
+            0xC3 | 0xCB => {
+                kind = Kind::Return;
+                (false, Immediate::None)
+            }
+            0xC4 | 0xC5 => (true, Immediate::None),
+            0xC7 => (true, Immediate::Z),
+            0xC8 => (false, Immediate::Enter),
+            0xC9 | 0xCC | 0xCE | 0xCF => (false, Immediate::None),
+            0xCD => (false, Immediate::Byte),
+            0xD0..=0xD3 | 0xD8..=0xDF => (true, Immediate::None),
+            0xD4 | 0xD5 => (false, Immediate::Byte),
+            0xD6 | 0xD7 => (false, Immediate::None),
+            0xE0..=0xE3 => return self.relative_branch(Kind::Loop, 1),
+            0xE4..=0xE7 => (false, Immediate::Byte),
+            0xE8 => return self.relative_branch(Kind::Call, self.branch_size()),
+            0xE9 => return self.relative_branch(Kind::Jump, self.branch_size()),
+            0xEA => (false, Immediate::Far),
+            0xEB => return self.relative_branch(Kind::Jump, 1),
+            0xEC..=0xEF | 0xF1 | 0xF4 | 0xF5 | 0xF8..=0xFD => (false, Immediate::None),
+            0xF6 | 0xF7 => {
+                // Only `test r/m, imm` (/0 and /1) has an immediate in this group.
+                let reg = self.modrm()?;
+                let immediate = match (reg, opcode) {
+                    (0 | 1, 0xF6) => Immediate::Byte,
+                    (0 | 1, _) => Immediate::Z,
+                    _ => Immediate::None,
+                };
+                self.immediate(immediate)?;
+                return Some(Kind::Other);
+            }
+            0xFE => (true, Immediate::None),
+            0xFF => {
+                let reg = self.modrm()?;
+                return Some(match reg {
+                    2 | 3 => Kind::IndirectCall,
+                    4 | 5 => Kind::IndirectJump,
+                    _ => Kind::Other,
+                });
+            }
+            // Prefixes are consumed before the opcode is read.
+            0x64..=0x67 | 0xF0 | 0xF2 | 0xF3 => return None,
+        };
+
+        if has_modrm {
+            self.modrm()?;
+        }
+        self.immediate(immediate)?;
+        Some(kind)
+    }
+
+    fn two_byte(&mut self) -> Option<Kind> {
+        let opcode = self.next()?;
+        match opcode {
+            0x80..=0x8F => {
+                return self.relative_branch(
+                    Kind::ConditionalJump {
+                        condition: opcode & 0x0F,
+                    },
+                    self.branch_size(),
+                );
+            }
+            0x38 => {
+                self.next()?;
+                self.modrm()?;
+                return Some(Kind::Other);
+            }
+            0x3A => {
+                self.next()?;
+                self.modrm()?;
+                self.immediate(Immediate::Byte)?;
+                return Some(Kind::Other);
+            }
+            _ => {}
+        }
+
+        let has_modrm = !matches!(
+            opcode,
+            0x04..=0x0C | 0x0E | 0x30..=0x37 | 0x39 | 0x3B..=0x3F | 0x77 | 0xA0..=0xA2 | 0xA8..=0xAA
+                | 0xC8..=0xCF
+        );
+        let has_immediate = matches!(
+            opcode,
+            0x0F | 0x70..=0x73 | 0xA4 | 0xAC | 0xBA | 0xC2 | 0xC4..=0xC6
+        );
+
+        if has_modrm {
+            self.modrm()?;
+        }
+        if has_immediate {
+            self.immediate(Immediate::Byte)?;
+        }
+        Some(Kind::Other)
+    }
+
+    /// In 32-bit mode, `C4`, `C5` and `62` are only VEX/EVEX prefixes if the following byte
+    /// would be a register-form ModRM; otherwise they are `les`, `lds` and `bound`.
+    fn is_vex_or_evex(&self) -> Option<bool> {
+        Some(self.mode == Mode::X64 || self.peek()? & 0xC0 == 0xC0)
+    }
+
+    fn vex_or_evex(&mut self, prefix: u8) -> Option<Kind> {
+        let map = match prefix {
+            0xC5 => {
+                self.next()?;
+                1
+            }
+            0xC4 => {
+                let map = self.next()? & 0x1F;
+                self.next()?;
+                map
+            }
+            _ => {
+                let map = self.next()? & 0x07;
+                self.next()?;
+                self.next()?;
+                map
+            }
+        };
+
+        let opcode = self.next()?;
+        // `vzeroupper` and `vzeroall` are the only VEX instructions without a ModRM byte.
+        if !(prefix != 0x62 && map == 1 && opcode == 0x77) {
+            self.modrm()?;
+        }
+        if map == 3 || (map == 1 && matches!(opcode, 0x70..=0x73 | 0xC2 | 0xC4..=0xC6)) {
+            self.immediate(Immediate::Byte)?;
+        }
+        Some(Kind::Other)
+    }
+
+    /// Consumes a ModRM byte and any SIB and displacement bytes that follow it, returning the
+    /// `reg` field.
+    fn modrm(&mut self) -> Option<u8> {
+        let modrm = self.next()?;
+        let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 0x07, modrm & 0x07);
+        if md == 3 {
+            return Some(reg);
+        }
+
+        let displacement_size = if self.mode == Mode::X86 && self.address_size_override {
+            match (md, rm) {
+                (0, 6) | (2, _) => 2,
+                (1, _) => 1,
+                _ => 0,
+            }
+        } else {
+            let mut size = match md {
+                1 => 1,
+                2 => 4,
+                _ => 0,
+            };
+            if rm == 4 && self.next()? & 0x07 == 5 && md == 0 {
+                size = 4;
+            }
+            if md == 0 && rm == 5 {
+                size = 4;
+                if self.mode == Mode::X64 {
+                    self.relative = Some(self.read_relative(4)?);
+                }
+            }
+            size
+        };
+
+        self.skip(displacement_size)?;
+        Some(reg)
+    }
+
+    fn immediate(&mut self, immediate: Immediate) -> Option<()> {
+        let z = if self.operand_size_override { 2 } else { 4 };
+        let size = match immediate {
+            Immediate::None => 0,
+            Immediate::Byte => 1,
+            Immediate::Word => 2,
+            Immediate::Z => z,
+            Immediate::V if self.rex_w => 8,
+            Immediate::V => z,
+            Immediate::Address => match (self.mode, self.address_size_override) {
+                (Mode::X64, false) => 8,
+                (Mode::X64, true) | (Mode::X86, false) => 4,
+                (Mode::X86, true) => 2,
+            },
+            Immediate::Far => z + 2,
+            Immediate::Enter => 3,
+        };
+        self.skip(size)
+    }
+
+    /// The size of the displacement of a near `call`, `jmp` or `jcc`. The operand size prefix
+    /// is ignored for these in 64-bit mode.
+    fn branch_size(&self) -> usize {
+        if self.mode == Mode::X86 && self.operand_size_override {
+            2
+        } else {
+            4
+        }
+    }
+
+    fn relative_branch(&mut self, kind: Kind, size: usize) -> Option<Kind> {
+        self.relative = Some(self.read_relative(size)?);
+        self.skip(size)?;
+        Some(kind)
+    }
+
+    fn read_relative(&self, size: usize) -> Option<Relative> {
+        let bytes = self.bytes.get(self.position..self.position + size)?;
+        let displacement = match size {
+            1 => bytes[0] as i8 as isize,
+            2 => i16::from_le_bytes(bytes.try_into().ok()?) as isize,
+            _ => i32::from_le_bytes(bytes.try_into().ok()?) as isize,
+        };
+        Some(Relative {
+            offset: self.position,
+            size,
+            displacement,
+        })
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        if self.position + count > self.bytes.len() {
+            return None;
+        }
+        self.position += count;
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn length(bytes: &[u8], mode: Mode) -> Option<usize> {
+        decode(bytes, mode).map(|instruction| instruction.length)
+    }
+
+    #[test]
+    fn prefixes() {
+        // lock cmpxchg [rdx], rcx
+        assert_eq!(length(&[0xF0, 0x48, 0x0F, 0xB1, 0x0A], Mode::X64), Some(5));
+        // rep movsb
+        assert_eq!(length(&[0xF3, 0xA4], Mode::X64), Some(2));
+        // mov rax, gs:[0x28]
+        let bytes = [0x65, 0x48, 0x8B, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00];
+        assert_eq!(length(&bytes, Mode::X64), Some(9));
+        // Redundant REX prefixes are part of the instruction, and only the last one counts.
+        assert_eq!(length(&[0x48, 0x48, 0x90], Mode::X64), Some(3));
+        let mov = [0x48, 0x40, 0xB8, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(length(&mov, Mode::X64), Some(7));
+        assert_eq!(length(&[0x40, 0x48, 0xB8], Mode::X64), None);
+    }
+
+    #[test]
+    fn modrm_and_sib() {
+        // mov rax, rcx
+        assert_eq!(length(&[0x48, 0x89, 0xC8], Mode::X64), Some(3));
+        // mov rax, [rsp+8]
+        assert_eq!(length(&[0x48, 0x8B, 0x44, 0x24, 0x08], Mode::X64), Some(5));
+        // mov [rsp+0x100], rax
+        let bytes = [0x48, 0x89, 0x84, 0x24, 0x00, 0x01, 0x00, 0x00];
+        assert_eq!(length(&bytes, Mode::X64), Some(8));
+        // lea rax, [rcx*4+0x10] (SIB with no base)
+        let bytes = [0x48, 0x8D, 0x04, 0x8D, 0x10, 0x00, 0x00, 0x00];
+        assert_eq!(length(&bytes, Mode::X64), Some(8));
+        // mov eax, [bp+8] with 16-bit addressing
+        assert_eq!(length(&[0x67, 0x8B, 0x46, 0x08], Mode::X86), Some(4));
+        // mov eax, [0x1234] with 16-bit addressing
+        assert_eq!(length(&[0x67, 0x8B, 0x06, 0x34, 0x12], Mode::X86), Some(5));
+    }
+
+    #[test]
+    fn operand_size_override() {
+        // add cx, 0x1234
+        assert_eq!(length(&[0x66, 0x81, 0xC1, 0x34, 0x12], Mode::X64), Some(5));
+        // mov ax, 0x1234
+        assert_eq!(length(&[0x66, 0xB8, 0x34, 0x12], Mode::X64), Some(4));
+        // mov word ptr [rax], 0x1234
+        assert_eq!(length(&[0x66, 0xC7, 0x00, 0x34, 0x12], Mode::X64), Some(5));
+        // test ax, 0x1234
+        assert_eq!(length(&[0x66, 0xF7, 0xC0, 0x34, 0x12], Mode::X86), Some(5));
+        // Only `test` has an immediate in the F7 group: neg eax
+        assert_eq!(length(&[0xF7, 0xD8], Mode::X86), Some(2));
+    }
+
+    #[test]
+    fn mov_imm64() {
+        let mut bytes = vec![0x48, 0xB8];
+        bytes.extend(0x1122_3344_5566_7788_u64.to_le_bytes());
+        assert_eq!(length(&bytes, Mode::X64), Some(10));
+        // mov r11, imm64
+        bytes[0] = 0x49;
+        bytes[1] = 0xBB;
+        assert_eq!(length(&bytes, Mode::X64), Some(10));
+        // Without REX.W, the immediate is 32 bits.
+        assert_eq!(length(&bytes[1..], Mode::X64), Some(5));
+        // 0x48 is `dec eax` in 32-bit mode.
+        assert_eq!(length(&bytes, Mode::X86), Some(1));
+    }
+
+    #[test]
+    fn vex() {
+        // vzeroupper
+        assert_eq!(length(&[0xC5, 0xF8, 0x77], Mode::X64), Some(3));
+        // vmovdqu xmm0, [rcx]
+        assert_eq!(length(&[0xC5, 0xFA, 0x6F, 0x01], Mode::X64), Some(4));
+        // vpshufd xmm0, xmm1, 0x1B
+        assert_eq!(length(&[0xC5, 0xF9, 0x70, 0xC1, 0x1B], Mode::X64), Some(5));
+        // les eax, [ecx] is not VEX in 32-bit mode.
+        assert_eq!(length(&[0xC4, 0x01], Mode::X86), Some(2));
+    }
+
+    #[test]
+    fn invalid_or_truncated() {
+        assert_eq!(decode(&[], Mode::X64), None);
+        assert_eq!(decode(&[0x48, 0xB8, 0x01, 0x02], Mode::X64), None);
+        assert_eq!(decode(&[0xE8, 0x00, 0x00], Mode::X64), None);
+        assert_eq!(decode(&[0x8B, 0x44, 0x24], Mode::X64), None);
+        // push es
+        assert_eq!(decode(&[0x06], Mode::X64), None);
+        assert_eq!(length(&[0x06], Mode::X86), Some(1));
+    }
+
+    #[test]
+    fn relative_branches() {
+        let jmp = decode(&[0xEB, 0xFE], Mode::X64).unwrap();
+        assert_eq!(jmp.kind, Kind::Jump);
+        assert_eq!(
+            jmp.relative,
+            Some(Relative {
+                offset: 1,
+                size: 1,
+                displacement: -2
+            })
+        );
+        assert_eq!(jmp.target(0x1000), Some(0x1000));
+        assert!(jmp.is_relative_branch());
+        assert!(!jmp.is_rip_relative());
+
+        let call = decode(&[0xE8, 0x00, 0x01, 0x00, 0x00], Mode::X64).unwrap();
+        assert_eq!(call.kind, Kind::Call);
+        assert_eq!(call.target(0x1000), Some(0x1105));
+
+        let jcc = decode(&[0x0F, 0x84, 0xF0, 0xFF, 0xFF, 0xFF], Mode::X64).unwrap();
+        assert_eq!(jcc.kind, Kind::ConditionalJump { condition: 4 });
+        assert_eq!(
+            jcc.relative,
+            Some(Relative {
+                offset: 2,
+                size: 4,
+                displacement: -0x10
+            })
+        );
+        assert_eq!(jcc.target(0x1000), Some(0x1000 + 6 - 0x10));
+
+        let jcc = decode(&[0x7F, 0x10], Mode::X64).unwrap();
+        assert_eq!(jcc.kind, Kind::ConditionalJump { condition: 0xF });
+        assert_eq!(jcc.target(0x1000), Some(0x1012));
+
+        let jrcxz = decode(&[0xE3, 0x10], Mode::X64).unwrap();
+        assert_eq!(jrcxz.kind, Kind::Loop);
+        assert_eq!(jrcxz.target(0x1000), Some(0x1012));
+
+        // The operand size prefix is ignored in 64-bit mode, but selects rel16 in 32-bit mode.
+        let bytes = [0x66, 0xE9, 0x10, 0x00, 0x00, 0x00];
+        assert_eq!(length(&bytes, Mode::X64), Some(6));
+        let jmp = decode(&bytes, Mode::X86).unwrap();
+        assert_eq!(jmp.length, 4);
+        assert_eq!(jmp.relative.unwrap().size, 2);
+        assert_eq!(jmp.target(0x1000), Some(0x1014));
+    }
+
+    #[test]
+    fn indirect_branches_and_returns() {
+        assert_eq!(decode(&[0xFF, 0xD0], Mode::X64).unwrap().kind, Kind::IndirectCall);
+        assert_eq!(decode(&[0xFF, 0xE0], Mode::X64).unwrap().kind, Kind::IndirectJump);
+        assert_eq!(decode(&[0xC3], Mode::X64).unwrap().kind, Kind::Return);
+        let ret = decode(&[0xC2, 0x08, 0x00], Mode::X86).unwrap();
+        assert_eq!((ret.length, ret.kind), (3, Kind::Return));
+        assert_eq!(decode(&[0x90], Mode::X64).unwrap().relative, None);
+    }
+
+    #[test]
+    fn rip_relative() {
+        // mov rax, [rip+0x10]
+        let mov = decode(&[0x48, 0x8B, 0x05, 0x10, 0x00, 0x00, 0x00], Mode::X64).unwrap();
+        assert_eq!(mov.length, 7);
+        assert_eq!(
+            mov.relative,
+            Some(Relative {
+                offset: 3,
+                size: 4,
+                displacement: 0x10
+            })
+        );
+        assert!(mov.is_rip_relative());
+        assert_eq!(mov.target(0x1000), Some(0x1017));
+
+        // mov dword ptr [rip+0x10], 1: relative to the end of the immediate
+        let bytes = [0xC7, 0x05, 0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        let mov = decode(&bytes, Mode::X64).unwrap();
+        assert_eq!(mov.length, 10);
+        assert_eq!(mov.relative.unwrap().offset, 2);
+        assert_eq!(mov.target(0x1000), Some(0x101A));
+
+        // jmp qword ptr [rip+0]
+        let jmp = decode(&[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00], Mode::X64).unwrap();
+        assert_eq!(jmp.kind, Kind::IndirectJump);
+        assert!(jmp.is_rip_relative());
+
+        // The same encoding is an absolute address in 32-bit mode.
+        let mov = decode(&[0x8B, 0x05, 0x10, 0x00, 0x00, 0x00], Mode::X86).unwrap();
+        assert_eq!((mov.length, mov.relative), (6, None));
+
+        // [disp32] through a SIB byte is never RIP-relative.
+        let bytes = [0x8B, 0x04, 0x25, 0x10, 0x00, 0x00, 0x00];
+        assert_eq!(decode(&bytes, Mode::X64).unwrap().relative, None);
+    }
+}
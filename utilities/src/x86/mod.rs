@@ -0,0 +1,10 @@
+//! Minimal x86/x64 instruction decoding and relocation, used to find and rewrite branch
+//! targets and RIP-relative operands.
+//!
+//! This module is platform-independent so that it can be exercised against plain byte buffers.
+
+mod decoder;
+mod relocate;
+
+pub use decoder::{decode, Instruction, Kind, Mode, Relative, MAX_INSTRUCTION_LENGTH};
+pub use relocate::{jump, relocate, Relocation};
@@ -0,0 +1,372 @@
+use super::{decode, Instruction, Kind, Mode};
+use crate::error::{Error, Result};
+
+/// Instructions that have been copied to a new address, with their relative operands adjusted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// The relocated instructions.
+    pub bytes: Vec<u8>,
+    /// The number of bytes of the original code that were relocated.
+    pub source_length: usize,
+    /// The offset of each relocated instruction in the original code and in `bytes`.
+    pub offsets: Vec<(usize, usize)>,
+}
+
+/// Relocates whole instructions from the start of `code`, located at `from`, so that they can
+/// be executed at `to`. Instructions are relocated until at least `min_length` bytes of the
+/// original code are covered.
+///
+/// Relative branches are rewritten to reach their original targets, switching to a longer
+/// encoding (or, on x64, an absolute jump) when the rel8/rel32 form is out of range.
+/// RIP-relative memory operands are rewritten as well, but cannot be widened, so their targets
+/// must be within rel32 range of `to`.
+///
+/// Fails if the instructions branch back into the relocated range (other than to `from`
+/// itself), or if control flow leaves the range before `min_length` bytes are covered.
+pub fn relocate(
+    code: &[u8],
+    from: usize,
+    to: usize,
+    min_length: usize,
+    mode: Mode,
+) -> Result<Relocation> {
+    let mut bytes = vec![];
+    let mut source_length = 0;
+    let mut offsets = vec![];
+    let mut branches = vec![];
+
+    while source_length < min_length {
+        let address = from + source_length;
+        let instruction = code
+            .get(source_length..)
+            .and_then(|code| decode(code, mode))
+            .ok_or(Error::InstructionDecodeFailed { address })?;
+        let original = &code[source_length..source_length + instruction.length];
+        let at = to + bytes.len();
+        let target = instruction.target(address).unwrap_or_default();
+        offsets.push((source_length, bytes.len()));
+
+        if instruction.is_relative_branch() {
+            branches.push((address, target));
+            bytes.extend(relocate_branch(original, &instruction, at, target, mode));
+        } else if let Some(relative) = instruction.relative {
+            let displacement = rel32(mode, at + instruction.length, target)
+                .ok_or(Error::TargetOutOfRange { address, target })?;
+            let mut relocated = original.to_vec();
+            relocated[relative.offset..relative.offset + 4]
+                .copy_from_slice(&displacement.to_le_bytes());
+            bytes.extend(relocated);
+        } else {
+            bytes.extend_from_slice(original);
+        }
+
+        source_length += instruction.length;
+        if source_length < min_length
+            && matches!(
+                instruction.kind,
+                Kind::Jump | Kind::IndirectJump | Kind::Return
+            )
+        {
+            return Err(Error::UnexpectedInstruction {
+                address,
+                expected: "instruction that falls through",
+            });
+        }
+    }
+
+    if let Some((address, target)) = branches
+        .into_iter()
+        .find(|(_, target)| (from + 1..from + source_length).contains(target))
+    {
+        return Err(Error::BranchIntoRelocatedCode { address, target });
+    }
+
+    Ok(Relocation {
+        bytes,
+        source_length,
+        offsets,
+    })
+}
+
+/// Encodes a jump located at `at` to `target`, using `jmp rel32` if it is in range and an
+/// absolute jump otherwise.
+pub fn jump(at: usize, target: usize, mode: Mode) -> Vec<u8> {
+    match rel32(mode, at + 5, target) {
+        Some(displacement) => [&[0xE9][..], &displacement.to_le_bytes()].concat(),
+        // jmp qword ptr [rip+0]; dq target
+        None => [
+            &[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00][..],
+            &(target as u64).to_le_bytes(),
+        ]
+        .concat(),
+    }
+}
+
+fn relocate_branch(
+    original: &[u8],
+    instruction: &Instruction,
+    at: usize,
+    target: usize,
+    mode: Mode,
+) -> Vec<u8> {
+    match instruction.kind {
+        Kind::Call => match rel32(mode, at + 5, target) {
+            Some(displacement) => [&[0xE8][..], &displacement.to_le_bytes()].concat(),
+            // call qword ptr [rip+2]; jmp short +8; dq target
+            None => [
+                &[0xFF, 0x15, 0x02, 0x00, 0x00, 0x00, 0xEB, 0x08][..],
+                &(target as u64).to_le_bytes(),
+            ]
+            .concat(),
+        },
+        Kind::ConditionalJump { condition } => match rel32(mode, at + 6, target) {
+            Some(displacement) => {
+                [&[0x0F, 0x80 | condition][..], &displacement.to_le_bytes()].concat()
+            }
+            // Skip over an absolute jump if the condition is not met.
+            None => {
+                let far = jump(at + 2, target, mode);
+                [&[0x70 | (condition ^ 1), far.len() as u8][..], &far].concat()
+            }
+        },
+        Kind::Loop => {
+            // loop +2; jmp short +far; far: jmp target
+            let opcode = &original[..original.len() - 1];
+            let far = jump(at + opcode.len() + 3, target, mode);
+            [opcode, &[0x02, 0xEB, far.len() as u8], &far].concat()
+        }
+        _ => jump(at, target, mode),
+    }
+}
+
+/// Computes the rel32 displacement from `next_instruction` to `destination`, if it is in range.
+/// In 32-bit mode, every destination is in range.
+fn rel32(mode: Mode, next_instruction: usize, destination: usize) -> Option<i32> {
+    match mode {
+        Mode::X86 => Some((destination as u32).wrapping_sub(next_instruction as u32) as i32),
+        Mode::X64 => (destination as isize)
+            .wrapping_sub(next_instruction as isize)
+            .try_into()
+            .ok(),
+    }
+}
+
+// The far addresses used to exercise the absolute forms only fit in a 64-bit `usize`.
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests {
+    use super::*;
+
+    const FROM: usize = 0x1000;
+    const NEAR: usize = 0x2000;
+    const FAR: usize = 0x7000_0000_0000;
+
+    fn rel32_bytes(next_instruction: usize, destination: usize) -> [u8; 4] {
+        ((destination as isize - next_instruction as isize) as i32).to_le_bytes()
+    }
+
+    fn absolute(prefix: &[u8], destination: usize) -> Vec<u8> {
+        [prefix, &(destination as u64).to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn copies_plain_instructions() {
+        // push rbp; mov rbp, rsp; sub rsp, 0x20; nop
+        let code = [0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x20, 0x90];
+        let relocation = relocate(&code, FROM, NEAR, 5, Mode::X64).unwrap();
+        assert_eq!(relocation.bytes, &code[..8]);
+        assert_eq!(relocation.source_length, 8);
+        assert_eq!(relocation.offsets, [(0, 0), (1, 1), (4, 4)]);
+    }
+
+    #[test]
+    fn widens_rel8_jump() {
+        let relocation = relocate(&[0xEB, 0x10], FROM, NEAR, 2, Mode::X64).unwrap();
+        let expected = [&[0xE9][..], &rel32_bytes(NEAR + 5, FROM + 0x12)].concat();
+        assert_eq!(relocation.bytes, expected);
+        assert_eq!(relocation.source_length, 2);
+    }
+
+    #[test]
+    fn widens_rel8_conditional_jump() {
+        let code = [0x74, 0x10, 0x90, 0x90, 0x90];
+        let relocation = relocate(&code, FROM, NEAR, 5, Mode::X64).unwrap();
+        let expected = [
+            &[0x0F, 0x84][..],
+            &rel32_bytes(NEAR + 6, FROM + 0x12),
+            &[0x90, 0x90, 0x90],
+        ]
+        .concat();
+        assert_eq!(relocation.bytes, expected);
+        assert_eq!(relocation.offsets, [(0, 0), (2, 6), (3, 7), (4, 8)]);
+    }
+
+    #[test]
+    fn conditional_jump_beyond_rel32() {
+        let code = [0x7C, 0x10, 0x90, 0x90, 0x90];
+        let relocation = relocate(&code, FROM, FAR, 5, Mode::X64).unwrap();
+        // jge +14; jmp qword ptr [rip+0]; dq target
+        let expected = [
+            &[0x7D, 0x0E][..],
+            &absolute(&[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00], FROM + 0x12),
+            &[0x90, 0x90, 0x90],
+        ]
+        .concat();
+        assert_eq!(relocation.bytes, expected);
+    }
+
+    #[test]
+    fn expands_loop() {
+        let relocation = relocate(&[0xE2, 0x10], FROM, NEAR, 2, Mode::X64).unwrap();
+        // loop +2; jmp short +5; jmp target
+        let expected = [
+            &[0xE2, 0x02, 0xEB, 0x05, 0xE9][..],
+            &rel32_bytes(NEAR + 9, FROM + 0x12),
+        ]
+        .concat();
+        assert_eq!(relocation.bytes, expected);
+    }
+
+    #[test]
+    fn expands_jrcxz_beyond_rel32() {
+        let relocation = relocate(&[0xE3, 0x10], FROM, FAR, 2, Mode::X64).unwrap();
+        // jrcxz +2; jmp short +14; jmp qword ptr [rip+0]; dq target
+        let expected = [
+            &[0xE3, 0x02, 0xEB, 0x0E][..],
+            &absolute(&[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00], FROM + 0x12),
+        ]
+        .concat();
+        assert_eq!(relocation.bytes, expected);
+    }
+
+    #[test]
+    fn expands_jcxz_with_prefix() {
+        // jcxz in 32-bit mode keeps its address size prefix.
+        let relocation = relocate(&[0x67, 0xE3, 0x10], FROM, NEAR, 3, Mode::X86).unwrap();
+        let expected = [
+            &[0x67, 0xE3, 0x02, 0xEB, 0x05, 0xE9][..],
+            &rel32_bytes(NEAR + 10, FROM + 0x13),
+        ]
+        .concat();
+        assert_eq!(relocation.bytes, expected);
+    }
+
+    #[test]
+    fn relocates_call() {
+        let code = [0xE8, 0x00, 0x01, 0x00, 0x00];
+        let relocation = relocate(&code, FROM, NEAR, 5, Mode::X64).unwrap();
+        let expected = [&[0xE8][..], &rel32_bytes(NEAR + 5, FROM + 0x105)].concat();
+        assert_eq!(relocation.bytes, expected);
+    }
+
+    #[test]
+    fn call_beyond_rel32() {
+        let code = [0xE8, 0x00, 0x01, 0x00, 0x00];
+        let relocation = relocate(&code, FROM, FAR, 5, Mode::X64).unwrap();
+        // call qword ptr [rip+2]; jmp short +8; dq target
+        let expected = absolute(
+            &[0xFF, 0x15, 0x02, 0x00, 0x00, 0x00, 0xEB, 0x08],
+            FROM + 0x105,
+        );
+        assert_eq!(relocation.bytes, expected);
+    }
+
+    #[test]
+    fn jump_beyond_rel32() {
+        let code = [0xE9, 0x00, 0x01, 0x00, 0x00];
+        let relocation = relocate(&code, FROM, FAR, 5, Mode::X64).unwrap();
+        let expected = absolute(&[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00], FROM + 0x105);
+        assert_eq!(relocation.bytes, expected);
+    }
+
+    #[test]
+    fn every_target_is_in_range_in_x86() {
+        let code = [0xE8, 0x00, 0x01, 0x00, 0x00];
+        let to = 0xF000_0000;
+        let relocation = relocate(&code, FROM, to, 5, Mode::X86).unwrap();
+        let displacement = ((FROM + 0x105) as u32).wrapping_sub(to as u32 + 5);
+        let expected = [&[0xE8][..], &displacement.to_le_bytes()].concat();
+        assert_eq!(relocation.bytes, expected);
+    }
+
+    #[test]
+    fn fixes_up_rip_relative_displacement() {
+        // mov rax, [rip+0x10]
+        let code = [0x48, 0x8B, 0x05, 0x10, 0x00, 0x00, 0x00];
+        let relocation = relocate(&code, FROM, NEAR, 5, Mode::X64).unwrap();
+        let expected = [&[0x48, 0x8B, 0x05][..], &rel32_bytes(NEAR + 7, FROM + 0x17)].concat();
+        assert_eq!(relocation.bytes, expected);
+
+        // mov dword ptr [rip+0x10], 1
+        let code = [0xC7, 0x05, 0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        let relocation = relocate(&code, FROM, NEAR, 5, Mode::X64).unwrap();
+        let expected = [
+            &[0xC7, 0x05][..],
+            &rel32_bytes(NEAR + 10, FROM + 0x1A),
+            &[0x01, 0x00, 0x00, 0x00],
+        ]
+        .concat();
+        assert_eq!(relocation.bytes, expected);
+    }
+
+    #[test]
+    fn rip_relative_beyond_rel32() {
+        let code = [0x48, 0x8B, 0x05, 0x10, 0x00, 0x00, 0x00];
+        let error = relocate(&code, FROM, FAR, 5, Mode::X64).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::TargetOutOfRange {
+                address: FROM,
+                target: 0x1017
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_branch_into_relocated_code() {
+        let code = [0x74, 0x01, 0x90, 0x90, 0x90];
+        let error = relocate(&code, FROM, NEAR, 5, Mode::X64).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::BranchIntoRelocatedCode {
+                address: FROM,
+                target: 0x1003
+            }
+        ));
+
+        // A branch back to the start is fine, as it is not overwritten by anything but the hook.
+        assert!(relocate(&[0xEB, 0xFE], FROM, NEAR, 2, Mode::X64).is_ok());
+    }
+
+    #[test]
+    fn rejects_early_return() {
+        let code = [0xC3, 0x90, 0x90, 0x90, 0x90];
+        let error = relocate(&code, FROM, NEAR, 5, Mode::X64).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::UnexpectedInstruction { address: FROM, .. }
+        ));
+    }
+
+    #[test]
+    fn rejects_undecodable_code() {
+        let error = relocate(&[0x90, 0x06], FROM, NEAR, 2, Mode::X64).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InstructionDecodeFailed { address: 0x1001 }
+        ));
+        // Running out of code is a decoding failure as well.
+        let error = relocate(&[0x90, 0x90], FROM, NEAR, 5, Mode::X64).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InstructionDecodeFailed { address: 0x1002 }
+        ));
+    }
+
+    #[test]
+    fn encodes_jumps() {
+        let expected = [&[0xE9][..], &rel32_bytes(NEAR + 5, FROM)].concat();
+        assert_eq!(jump(NEAR, FROM, Mode::X64), expected);
+        let expected = absolute(&[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00], FROM);
+        assert_eq!(jump(FAR, FROM, Mode::X64), expected);
+    }
+}